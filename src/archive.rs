@@ -0,0 +1,58 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Component, Path},
+    sync::Mutex,
+};
+
+use zip::{result::ZipResult, write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Whether `path` names a zip file, which the outputs are written into
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Writes the outputs into a zip file instead of the output directory
+pub struct ZipOutput {
+    writer: Mutex<Option<ZipWriter<File>>>,
+}
+
+impl ZipOutput {
+    pub fn create(path: &Path) -> ZipResult<Self> {
+        Ok(Self {
+            writer: Mutex::new(Some(ZipWriter::new(File::create(path)?))),
+        })
+    }
+
+    /// Adds `bytes` under `name`, a path relative to the root of the archive
+    pub fn add(&self, name: &Path, bytes: &[u8]) -> ZipResult<()> {
+        // Zip entries always use forward slashes
+        let name = name
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        // WebP is compressed already, deflating it again only costs time
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+        let mut writer = self.writer.lock().unwrap();
+        let writer = writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the archive is already finished"))?;
+        writer.start_file(name, options)?;
+        writer.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Writes the central directory, without which the archive can't be read
+    pub fn finish(&self) -> ZipResult<()> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
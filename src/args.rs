@@ -1,55 +1,704 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use glob::Pattern;
+use image::Rgb;
+
+use crate::{archive, file_utils};
 use std::{
     env,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 #[derive(Parser)]
 pub struct Cli {
-    /// Input path
-    input: PathBuf,
+    /// Input paths, files or directories. The output mirrors their structure
+    /// from the deepest directory they share. With `--files-from`, a single
+    /// directory whose structure is mirrored, the current directory by default.
+    /// Inputs with `*`, `?` or `[` that aren't an existing path are expanded
+    /// as glob patterns, like `'photos/**/*.jpg'`
+    #[arg(required_unless_present = "files_from")]
+    input: Vec<PathBuf>,
+
+    /// Expand every input as a glob pattern, even one naming an existing path
+    #[arg(long)]
+    pub input_glob: bool,
+
+    /// Mirror the structure of the inputs in the output from this directory
+    /// instead. Images outside of it are written straight into the output
+    #[arg(long, conflicts_with = "watch")]
+    pub keep_structure_from: Option<PathBuf>,
 
-    /// Output path, uses root if not provided
+    /// Convert the files listed in this file, one path per line, instead of
+    /// walking the input directory. `-` reads the list from stdin
+    #[arg(long, conflicts_with = "watch")]
+    pub files_from: Option<PathBuf>,
+
+    /// Output path, uses root if not provided. A path ending in `.zip` writes
+    /// the outputs into that archive instead
     #[arg(short, long)]
     output: Option<PathBuf>,
 
-    /// Quality from 0 to 100
-    #[arg(short, long, default_value_t = 100)]
-    pub quality: u8,
+    /// Quality from 0 to 100, fractions like 82.5 included. 100 by default
+    #[arg(short, long, value_parser = parse_quality)]
+    quality: Option<f32>,
+
+    /// Quality for JPEG sources, takes precedence over `--quality`
+    #[arg(long, value_parser = parse_quality)]
+    pub quality_jpeg: Option<f32>,
+
+    /// Quality for PNG sources, takes precedence over `--quality`
+    #[arg(long, value_parser = parse_quality)]
+    quality_png: Option<f32>,
+
+    /// Encode every image losslessly, which is what the default quality of
+    /// 100 does. Takes the place of `--quality` and the per-format qualities
+    #[arg(short, long, conflicts_with_all = ["quality", "quality_jpeg", "quality_png"])]
+    lossless: bool,
+
+    /// Encode lossy even at quality 100
+    #[arg(long, conflicts_with = "lossless")]
+    lossy: bool,
+
+    /// Encode PNG sources losslessly and everything else lossy at quality 80,
+    /// instead of everything losslessly. `--quality-jpeg` and `--quality-png`
+    /// still apply on top
+    #[arg(long, conflicts_with_all = ["quality", "lossless", "lossy"])]
+    pub smart_default: bool,
+
+    /// Encode each image lossy at the highest quality whose output fits in
+    /// this many bytes, found by encoding it a few times. The quality picked
+    /// is shown in an Effective Q column
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = TARGET_CONFLICTS)]
+    pub target_size: Option<u64>,
 
-    #[arg(short, long, default_value_t = 1)]
-    pub lossless: u8,
+    /// Encode each image lossy at the lowest quality whose output reaches
+    /// this PSNR in dB, found by encoding it a few times. The quality picked
+    /// is shown in an Effective Q column
+    #[arg(long, conflicts_with_all = TARGET_CONFLICTS, conflicts_with = "target_size")]
+    pub target_psnr: Option<f64>,
 
+    /// Encoding method from 0 to 6. Lower is faster, higher is slower but
+    /// produces smaller files
     #[arg(short, long, default_value_t = 6)]
-    pub method: u8,
+    method: u8,
 
+    /// Fastest encoding, same as `--method 0`
+    #[arg(long, conflicts_with_all = ["method", "best"])]
+    fast: bool,
+
+    /// Smallest files, same as `--method 6`
+    #[arg(long, conflicts_with = "method")]
+    best: bool,
+
+    /// Pick the method for each image from its pixel count: 6 under the first
+    /// number of megapixels, 4 up to the second and 2 above it, like
+    /// `--method-auto=0.5,8`. `1,16` when given without thresholds
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1,16",
+        conflicts_with_all = ["method", "fast", "best"]
+    )]
+    pub method_auto: Option<MethodAuto>,
+
+    /// Encode images with fewer distinct colors than this losslessly at
+    /// method 6, whatever the quality. Lossless does far better on sprites and
+    /// other palette-like images
+    #[arg(long)]
+    pub palette_threshold: Option<u32>,
+
+    /// Lossless preset from 0, fastest, to 9, smallest. Replaces `--quality`
+    /// and `--method` for lossless encodes, lossy ones still use them
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+    pub lossless_level: Option<u8>,
+
+    /// Levels of subdirectories to go into, 0 for only the images directly in
+    /// the input directory
     #[arg(long, default_value_t = 8)]
     pub max_depth: u16,
 
+    /// Leave out directories with this name or matching this glob pattern,
+    /// like `node_modules` or `cache-*`. Can be repeated
+    #[arg(long)]
+    pub exclude_dir: Vec<Pattern>,
+
+    /// Also convert hidden files and go into hidden directories, whose name
+    /// starts with a dot
+    #[arg(long)]
+    pub hidden: bool,
+
+    /// Keep the source as the output when the WebP comes out larger, under
+    /// its own name and extension
+    #[arg(long)]
+    pub use_initial_if_smaller: bool,
+
+    /// Refuse images with more pixels than this, which guards against small
+    /// files that decode to huge images. Defaults to the largest WebP image
+    #[arg(long, default_value_t = 16383 * 16383)]
+    pub max_pixels: u64,
+
+    /// Skip files smaller than this many bytes, where the WebP overhead
+    /// often makes the output larger than the source
     #[arg(long, default_value_t = 0)]
-    pub use_initial_if_smaller: u8,
+    pub min_size: u64,
+
+    /// Skip images whose longer side is shorter than this many pixels, like
+    /// icons that are fine as they are
+    #[arg(long)]
+    pub min_dimension: Option<u32>,
+
+    /// Skip images whose longer side is longer than this many pixels
+    #[arg(long)]
+    pub max_dimension: Option<u32>,
+
+    /// Copy files that can't be decoded as images to the output unchanged,
+    /// instead of failing them, to mirror a tree of mixed files
+    #[arg(long)]
+    pub copy_unconvertible: bool,
+
+    /// Re-encode images that are already WebP instead of skipping them
+    #[arg(long)]
+    pub force: bool,
+
+    /// Name outputs after the full source file name, `logo.png.webp` instead
+    /// of `logo.webp`, so `logo.png` and `logo.jpg` don't overwrite each other
+    #[arg(long)]
+    pub keep_original_extension: bool,
+
+    /// Extension of the outputs, for pipelines that want something else than
+    /// `webp`. Letters, digits, `-` and `_` only, without the leading dot
+    #[arg(long, default_value = "webp", value_parser = parse_extension)]
+    pub output_extension: String,
+
+    /// Name outputs after this template instead of the source, like
+    /// `{stem}_{width}x{height}_q{quality}.webp`. `{index}` is the position of
+    /// the image in the batch, from 1
+    #[arg(long)]
+    pub rename_pattern: Option<RenamePattern>,
+
+    /// Where outputs are written
+    #[arg(long, value_enum, default_value_t = OutputMode::Mirror)]
+    pub output_mode: OutputMode,
+
+    /// Frame to convert from animated GIF, PNG and WebP sources, or page from
+    /// multi-page TIFFs, from 0. Other images only have frame 0
+    #[arg(long)]
+    pub frame: Option<usize>,
+
+    /// Crop images to the `x,y,width,height` rectangle before encoding
+    #[arg(long)]
+    pub crop: Option<Crop>,
+
+    /// Make square thumbnails of this many pixels, scaling the shorter side
+    /// to the size and cutting the longer one around the center. Images
+    /// smaller than that are only cut, to a square of their shorter side
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub thumbnail: Option<u32>,
+
+    /// Scale images up to the `--thumbnail` size when they're smaller
+    #[arg(long, requires = "thumbnail")]
+    pub allow_upscale: bool,
+
+    /// Composite transparent images over this `#RRGGBB` color, for outputs
+    /// without an alpha channel. Alpha is kept otherwise
+    #[arg(long, value_parser = parse_hex_color)]
+    pub background: Option<Rgb<u8>>,
+
+    /// Convert images to grayscale before encoding, for scanned documents
+    #[arg(long)]
+    pub grayscale: bool,
+
+    /// libwebp's internal multi-threading, 1 for on and 0 for off.
+    /// Defaults to on for a single image and off when converting many, since
+    /// files are already encoded in parallel and enabling both oversubscribes the CPU
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=1))]
+    pub threads_webp: Option<u8>,
+
+    /// Quality of the alpha channel of lossy images, from 0 to 100. The
+    /// default of 100 keeps it lossless, for crisp edges with lossy colors
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub alpha_quality: u8,
+
+    /// Encode the colors lossy, even at quality 100, and the alpha channel
+    /// losslessly, for icons with antialiased edges
+    #[arg(long, conflicts_with_all = ["alpha_quality", "lossless"])]
+    pub lossless_alpha: bool,
+
+    /// Lowest quantizer quality libwebp may pick for lossy images, from 0 to
+    /// 100. Raising it keeps busy regions from getting blurrier than this
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub qmin: u8,
+
+    /// Highest quantizer quality libwebp may pick for lossy images, from 0 to
+    /// 100. Lowering it keeps flat regions from spending bytes past this
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub qmax: u8,
+
+    /// Lossy preprocessing bit flags: 1 smooths segments, 2 adds pseudo-random
+    /// dithering to reduce banding in gradients, 3 does both
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    pub preprocessing: u8,
+
+    /// Dithering from 0 to 100 added to lossy images as their colors are
+    /// converted for encoding, against banding in smooth gradients. Unlike
+    /// `--preprocessing 2`, whose amount libwebp picks from the quality
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub dither_strength: u8,
+
+    /// Lower libwebp's peak memory use at some speed cost. Only affects
+    /// lossless encoding
+    #[arg(long)]
+    pub low_memory: bool,
+
+    /// Retry images that fail to encode losslessly as lossy at quality 95
+    /// instead of skipping them
+    #[arg(long)]
+    pub fallback_lossy: bool,
+
+    /// Stop at the first image that fails to convert instead of going
+    /// through the rest. Images already being converted still finish
+    #[arg(long, conflicts_with = "watch")]
+    pub abort_on_first_error: bool,
+
+    /// Print libwebp's statistics of each encode to stderr: the size of each
+    /// part of the output, and the PSNR of each channel for lossy or the
+    /// transforms picked for lossless
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Print a line with the progress to stderr every this many seconds,
+    /// for logs where there's no terminal to watch
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_interval: Option<u64>,
+
+    /// Add a line to each progress print for every directory right under the
+    /// input, with how many of the files found in it so far are done
+    #[arg(long, requires = "stats_interval")]
+    pub show_progress_per_directory: bool,
+
+    /// Print each image that would be converted and where its output would
+    /// go, then exit without decoding or writing anything. Outputs that would
+    /// collide, overwrite their source or land in a directory that can't be
+    /// written to are all reported at the end, and make it exit with an error
+    #[arg(long, conflicts_with = "watch")]
+    pub list: bool,
+
+    /// Print the encoder settings the options resolve to, then exit without
+    /// converting anything. --quality-jpeg and --quality-png still apply on
+    /// top, to the files they're for
+    #[arg(long, conflicts_with_all = ["watch", "list"])]
+    pub print_config: bool,
+
+    /// Retry writing an output this many times when it fails with an error
+    /// that may go away, like a timeout on a network drive
+    #[arg(long, default_value_t = 0)]
+    pub retries: u32,
+
+    /// Fail files that take longer than this many seconds to convert, so a
+    /// pathological image doesn't stall the batch. libwebp can't be stopped,
+    /// so the encode goes on in the background until it's done, using a core.
+    /// Files fail without being started while as many of those are running
+    /// as there are threads
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: Option<u64>,
+
+    /// Keep running after the conversion, converting images as they are added
+    /// to the input directory, until interrupted
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Write `<output>.json` next to each output with the settings and sizes
+    /// it was produced with
+    #[arg(long)]
+    pub sidecar: bool,
+
+    /// Write the image as it was encoded to `<output>.orig.png`, and the
+    /// difference with what the WebP decodes to, magnified, to `<output>.diff.png`,
+    /// to spot compression artifacts
+    #[arg(long)]
+    pub compare: bool,
+
+    /// Record every converted source in this file, and skip the sources
+    /// it already lists, to resume an interrupted run
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Keep every output in this directory under the hash of its source's
+    /// content and the settings, and copy it from there when the same content
+    /// comes up again instead of encoding it. Hits aren't encoded, so it
+    /// can't be combined with --compare or --stats
+    #[arg(long, conflicts_with_all = ["compare", "stats"])]
+    pub content_cache: Option<PathBuf>,
+
+    /// Write how long each phase of every file took, walk, decode, resize,
+    /// encode and write, as CSV to this path. Needs a build with
+    /// `--features profile`
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    /// Write a CSV report with a row for every file to this path
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+
+    /// Show more details about each image
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Say why images that can't be read were rejected, like a truncated
+    /// file or a corrupt header, instead of only that they're not images
+    #[arg(long)]
+    pub verbose_errors: bool,
+
+    /// Print only the totals at the end, without a row per file
+    #[arg(long)]
+    pub summary_only: bool,
+
+    /// Order in which images are processed
+    #[arg(long, value_enum, default_value_t = Sort::Name)]
+    pub sort: Sort,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Sort {
+    /// By path, comparing numbers by value so `frame2` comes before `frame10`
+    Name,
+    /// By file size, smallest first
+    Size,
+    /// Order of discovery, which changes from run to run. Files start
+    /// converting while the directory walk is still running
+    None,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputMode {
+    /// Under the output directory, in the same subdirectories as the source
+    Mirror,
+    /// Next to each source, so no output directory can be given
+    Beside,
+    /// Straight into the output directory. Images named like an image from
+    /// another directory get their relative path in the name, so
+    /// `a/b/logo.png` becomes `a_b_logo.png.webp`
+    Flat,
+}
+
+/// Rectangle in pixels, from the top left corner
+#[derive(Clone, Copy, Debug)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| error.to_string())?;
+        let [x, y, width, height] = numbers[..] else {
+            return Err("expected x,y,width,height".to_string());
+        };
+        if width == 0 || height == 0 {
+            return Err("the width and height can't be 0".to_string());
+        }
+
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// Megapixel thresholds of `--method-auto`
+#[derive(Clone, Copy, Debug)]
+pub struct MethodAuto {
+    small: f64,
+    large: f64,
+}
+
+impl MethodAuto {
+    pub fn method_for(&self, width: u32, height: u32) -> u8 {
+        let megapixels = width as f64 * height as f64 / 1_000_000.0;
+        if megapixels < self.small {
+            6
+        } else if megapixels <= self.large {
+            4
+        } else {
+            2
+        }
+    }
+}
+
+impl FromStr for MethodAuto {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| error.to_string())?;
+        let [small, large] = numbers[..] else {
+            return Err("expected two megapixel thresholds, like 1,16".to_string());
+        };
+        if !(0.0 <= small && small <= large) {
+            return Err("the thresholds must be positive, the smaller one first".to_string());
+        }
+
+        Ok(MethodAuto { small, large })
+    }
+}
+
+/// Output file name with variables filled in for each image
+#[derive(Clone)]
+pub struct RenamePattern(Vec<PatternPart>);
+
+#[derive(Clone)]
+enum PatternPart {
+    Text(String),
+    Stem,
+    Width,
+    Height,
+    Quality,
+    Index,
+}
+
+/// Values of the `RenamePattern` variables for one image
+pub struct RenameValues<'a> {
+    pub stem: &'a str,
+    pub width: u32,
+    pub height: u32,
+    pub quality: f32,
+    pub index: usize,
+}
+
+impl RenamePattern {
+    pub fn expand(&self, values: &RenameValues) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                PatternPart::Text(text) => text.clone(),
+                PatternPart::Stem => values.stem.to_string(),
+                PatternPart::Width => values.width.to_string(),
+                PatternPart::Height => values.height.to_string(),
+                PatternPart::Quality => values.quality.to_string(),
+                PatternPart::Index => values.index.to_string(),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for RenamePattern {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.contains(['/', '\\']) {
+            return Err(
+                "the pattern is a file name, it can't contain a path separator".to_string(),
+            );
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(PatternPart::Text(rest[..start].to_string()));
+            }
+            let end = start
+                + rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed {{ in {}", value))?;
+            let part = match &rest[start + 1..end] {
+                "stem" => PatternPart::Stem,
+                "width" => PatternPart::Width,
+                "height" => PatternPart::Height,
+                "quality" => PatternPart::Quality,
+                "index" => PatternPart::Index,
+                name => {
+                    return Err(format!(
+                        "unknown variable {{{}}}, expected {{stem}}, {{width}}, {{height}}, {{quality}} or {{index}}",
+                        name
+                    ))
+                }
+            };
+            parts.push(part);
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(PatternPart::Text(rest.to_string()));
+        }
+
+        Ok(RenamePattern(parts))
+    }
+}
+
+/// Options that pick the quality or lossless themselves, or that can't know
+/// the quality a target search settled on
+const TARGET_CONFLICTS: [&str; 6] = [
+    "quality",
+    "lossless",
+    "quality_jpeg",
+    "quality_png",
+    "smart_default",
+    "content_cache",
+];
+
+fn parse_quality(value: &str) -> Result<f32, String> {
+    let quality: f32 = value.parse().map_err(|_| "expected a number".to_string())?;
+    if !(0.0..=100.0).contains(&quality) {
+        return Err("expected a quality from 0 to 100".to_string());
+    }
+    Ok(quality)
+}
+
+fn parse_extension(value: &str) -> Result<String, String> {
+    let is_sane = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if value.is_empty() || value.len() > 16 || !is_sane {
+        return Err("expected letters, digits, - or _, like webp".to_string());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_hex_color(value: &str) -> Result<Rgb<u8>, String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err("expected a #RRGGBB color".to_string());
+    }
+    let channel = |index: usize| {
+        u8::from_str_radix(&hex[index..index + 2], 16).map_err(|error| error.to_string())
+    };
+    Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
 }
 
 impl Cli {
-    pub fn input_path(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
-        if self.input.try_exists().is_err() {
-            Err(format!(
-                "The path: {} does not exist!",
-                &self.input.to_str().unwrap()
-            ))?
+    pub fn quality(&self) -> f32 {
+        let default = if self.smart_default { 80.0 } else { 100.0 };
+        self.quality.unwrap_or(default)
+    }
+
+    /// `--quality-png`, or 100 and so lossless with `--smart-default`
+    pub fn quality_png(&self) -> Option<f32> {
+        self.quality_png.or(self.smart_default.then_some(100.0))
+    }
+
+    /// Whether quality 100 is lossless, unless `--lossy` says otherwise.
+    /// `--lossless` leaves every quality at 100, so it's always lossless
+    pub fn lossless(&self) -> bool {
+        self.lossless || (!self.lossy && !self.lossless_alpha && !self.has_target())
+    }
+
+    /// Whether the quality is searched for with `--target-size` or
+    /// `--target-psnr`
+    pub fn has_target(&self) -> bool {
+        self.target_size.is_some() || self.target_psnr.is_some()
+    }
+
+    /// Whether `--quality 100` was asked for and turns lossless on without
+    /// `--lossless` saying so, which surprises those expecting lossy
+    pub fn is_implicitly_lossless(&self) -> bool {
+        self.quality == Some(100.0) && !self.lossy && !self.lossless_alpha
+    }
+
+    /// Settings that likely don't do what was meant, for the command line to
+    /// print once before converting
+    pub fn warnings(&self) -> Vec<&'static str> {
+        let mut warnings = Vec::new();
+        if self.is_implicitly_lossless() {
+            warnings.push("Quality 100 encodes losslessly, pass --lossy for lossy at quality 100");
+        }
+        // Per-format qualities below 100 make those images lossy, which dither
+        let lossy_formats = [self.quality_jpeg, self.quality_png()]
+            .into_iter()
+            .flatten()
+            .any(|quality| quality < 100.0);
+        let lossless = self.quality() == 100.0 && self.lossless();
+        if self.dither_strength > 0 && lossless && !lossy_formats {
+            warnings.push("--dither-strength only applies to lossy images, it does nothing here");
         }
+        warnings
+    }
 
-        if !self.input.is_file() && !self.input.is_dir() {
-            Err(format!(
-                "The path: {} does not exist!",
-                self.input.to_str().unwrap()
-            ))?
+    pub fn method(&self) -> u8 {
+        if self.fast {
+            0
+        } else if self.best {
+            6
+        } else {
+            self.method
+        }
+    }
+
+    /// The inputs, made absolute when there are several so they share a root
+    pub fn input_paths(&self) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        if self.input.is_empty() {
+            return Ok(vec![env::current_dir()?]);
+        }
+        let inputs = self.expanded_inputs()?;
+        for input in &inputs {
+            if input.try_exists().is_err() {
+                Err(format!("The path: {} does not exist!", input.display()))?
+            }
+
+            if !input.is_file() && !input.is_dir() {
+                Err(format!("The path: {} does not exist!", input.display()))?
+            }
         }
 
-        Ok(self.input.clone())
+        if inputs.len() == 1 {
+            return Ok(inputs);
+        }
+        Ok(inputs
+            .iter()
+            .map(std::path::absolute)
+            .collect::<Result<_, _>>()?)
     }
 
-    pub fn output_path(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    /// The inputs, with the glob patterns among them replaced by the paths
+    /// they match
+    fn expanded_inputs(&self) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut inputs = Vec::new();
+        for input in &self.input {
+            if !self.is_glob(input) {
+                inputs.push(input.clone());
+                continue;
+            }
+            let pattern = input
+                .to_str()
+                .ok_or_else(|| format!("The pattern: {} is not UTF-8", input.display()))?;
+            let matches = glob::glob(pattern)
+                .map_err(|error| format!("The pattern: {} is invalid, {}", pattern, error))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                Err(format!("The pattern: {} matches nothing!", pattern))?
+            }
+            inputs.extend(matches);
+        }
+        Ok(inputs)
+    }
+
+    /// Whether `input` is a glob pattern. Without `--input-glob`, a path that
+    /// exists is taken as it is, even with brackets in its name
+    fn is_glob(&self, input: &Path) -> bool {
+        self.input_glob || (!input.exists() && input.to_string_lossy().contains(['*', '?', '[']))
+    }
+
+    /// Takes what `input_paths` returned so globs are only expanded once
+    pub fn output_path(
+        &self,
+        input_paths: &[PathBuf],
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if self.output.is_some() && matches!(self.output_mode, OutputMode::Beside) {
+            Err("--output can't be used with --output-mode beside, which writes every output next to its source")?
+        }
         let output_dir = match &self.output {
             Some(path) => {
                 if path.is_absolute() {
@@ -58,13 +707,36 @@ impl Cli {
                     env::current_dir()?.join(path)
                 }
             }
-            None => self
-                .input
-                .parent()
-                .unwrap_or_else(|| Path::new("."))
-                .to_path_buf(),
+            None if self.input.is_empty() => env::current_dir()?,
+            None => match input_paths {
+                [] => env::current_dir()?,
+                [input] => input
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .to_path_buf(),
+                inputs => file_utils::common_root(
+                    &inputs
+                        .iter()
+                        .map(std::path::absolute)
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+            },
         };
 
+        // Checked once here rather than failing for every image in parallel
+        if output_dir.exists() && !output_dir.is_dir() && !archive::is_archive(&output_dir) {
+            let input_kind = match input_paths {
+                [input] if input.is_file() => "a file",
+                [_, _, ..] => "several paths",
+                _ => "a directory",
+            };
+            Err(format!(
+                "The output path: {} is a file, but the input is {}. The output has to be a directory!",
+                output_dir.display(),
+                input_kind
+            ))?
+        }
+
         Ok(output_dir)
     }
 }
@@ -0,0 +1,36 @@
+use std::{error::Error, path::Path};
+
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::webp_wrapper;
+
+/// Differences are multiplied by this, small ones would be invisible otherwise
+const DIFF_GAIN: u8 = 8;
+
+/// Writes the image as it was encoded and the difference with what the WebP
+/// decodes to next to the output, `logo.webp` gets `logo.orig.png` and
+/// `logo.diff.png`
+pub fn write(output_path: &Path, source: &DynamicImage, webp: &[u8]) -> Result<(), Box<dyn Error>> {
+    let decoded = webp_wrapper::decode_rgba(webp).ok_or("the output can't be decoded")?;
+    let source = source.to_rgba8();
+    let diff = RgbaImage::from_fn(source.width(), source.height(), |x, y| {
+        let (source, decoded) = (source.get_pixel(x, y).0, decoded.get_pixel(x, y).0);
+        let channel = |index: usize| {
+            source[index]
+                .abs_diff(decoded[index])
+                .saturating_mul(DIFF_GAIN)
+        };
+        // Alpha differences show in every channel, the diff itself is opaque
+        let alpha = channel(3);
+        Rgba([
+            channel(0).max(alpha),
+            channel(1).max(alpha),
+            channel(2).max(alpha),
+            255,
+        ])
+    });
+
+    source.save(output_path.with_extension("orig.png"))?;
+    diff.save(output_path.with_extension("diff.png"))?;
+    Ok(())
+}
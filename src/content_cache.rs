@@ -0,0 +1,42 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::file_utils;
+
+/// Names the cached output of `source` encoded with `settings`. Only the
+/// content counts, so a source that's touched or rewritten unchanged still hits
+pub fn key(source: &[u8], settings: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(settings.as_bytes());
+    hasher.update(source);
+    hasher.finalize().to_hex().to_string()
+}
+
+/// The output stored under `key`, if any
+pub fn get(dir: &Path, key: &str) -> Option<Vec<u8>> {
+    fs::read(entry_path(dir, key)).ok()
+}
+
+/// Stores `output` under `key`. It's written to a temporary file first, so
+/// another run reading the same entry never sees half of it. The temporary
+/// name is unique to each write, as threads of the same run can store
+/// identical sources at the same time
+pub fn put(dir: &Path, key: &str, output: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    file_utils::create_dir_all(dir)?;
+    let path = entry_path(dir, key);
+    let partial_path = path.with_extension(format!(
+        "{}-{}.partial",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&partial_path, output)?;
+    fs::rename(&partial_path, &path)
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(key).with_extension("webp")
+}
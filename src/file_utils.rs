@@ -1,53 +1,422 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    error::Error,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
-use crate::Depth;
+use glob::Pattern;
+use rayon::{
+    iter::{ParallelBridge, ParallelIterator},
+    ThreadPoolBuilder,
+};
+
+use crate::{
+    args::{self, Sort},
+    profile,
+};
+
+/// The input directory is at depth 0, its subdirectories at 1, and so on
+#[derive(Clone, Copy)]
+struct Depth {
+    current: u16,
+    max: u16,
+}
+
+/// Which directories the walk goes into
+#[derive(Clone)]
+pub struct WalkOptions {
+    /// Levels of subdirectories to go into, 0 for only the files directly in
+    /// the input directory
+    pub max_depth: u16,
+    /// Names or glob patterns of directories to leave out
+    pub exclude_dirs: Vec<Pattern>,
+    /// Include files and directories whose name starts with a dot
+    pub hidden: bool,
+}
+
+impl WalkOptions {
+    pub fn excludes_dir(&self, name: &OsStr) -> bool {
+        self.excludes_file(name)
+            || self
+                .exclude_dirs
+                .iter()
+                .any(|pattern| pattern.matches(&name.to_string_lossy()))
+    }
+
+    pub fn excludes_file(&self, name: &OsStr) -> bool {
+        !self.hidden && name.to_string_lossy().starts_with('.')
+    }
+}
+
+pub fn generate_walk_options(args: &args::Cli) -> WalkOptions {
+    WalkOptions {
+        max_depth: args.max_depth,
+        exclude_dirs: args.exclude_dir.clone(),
+        hidden: args.hidden,
+    }
+}
 
 pub struct Paths {
     pub input: InputPaths,
     pub output_root: PathBuf,
 }
 pub struct InputPaths {
+    /// Directory whose structure the output mirrors
     pub root: PathBuf,
     pub images: Vec<PathBuf>,
+    /// Images sharing their directory and file stem with another image, like
+    /// `a.png` and `a.jpg`, which would otherwise convert to the same `a.webp`.
+    /// Only the file stem has to match when the output is flattened
+    pub colliding: HashSet<PathBuf>,
+    /// Directories under the inputs that couldn't be read, and why. The
+    /// images in the rest are still there
+    pub unreadable: Vec<(PathBuf, io::Error)>,
 }
 
 impl Paths {
-    pub fn build(input_path: PathBuf, output_path: PathBuf, max_depth: u16) -> Paths {
-        let depth = Depth {
-            current: 0,
-            max: max_depth,
+    /// Walks every input, with their `common_root` as the root. Fails when
+    /// an input can't be read, while directories under them that can't be
+    /// are left in `unreadable` for the caller to report. The walk ends early,
+    /// with the images found so far, once `stopped` returns true
+    pub fn build(
+        input_paths: Vec<PathBuf>,
+        output_path: PathBuf,
+        walk_options: &WalkOptions,
+        sort: Sort,
+        flatten: bool,
+        stopped: &(dyn Fn() -> bool + Sync),
+    ) -> io::Result<Paths> {
+        check_readable(&input_paths)?;
+        let (sender, receiver) = mpsc::channel();
+        let unreadable = Mutex::new(Vec::new());
+        let fail =
+            |path: &Path, error| unreadable.lock().unwrap().push((path.to_path_buf(), error));
+        walk(&input_paths, walk_options, sender, &fail, stopped);
+
+        let all_files: Vec<PathBuf> = receiver.into_iter().collect();
+        let mut unreadable = unreadable.into_inner().unwrap();
+        // Found in parallel, in no particular order
+        unreadable.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let root = common_root(&input_paths);
+        let mut paths = Self::new(root, all_files, output_path, sort, flatten);
+        paths.input.unreadable = unreadable;
+        Ok(paths)
+    }
+
+    /// Takes the images from `list`, one path per line, or stdin for `-`.
+    /// Relative paths are relative to the current directory, and listed images
+    /// outside `root` are written straight into `output_path`
+    pub fn from_list(
+        list: &Path,
+        root: PathBuf,
+        output_path: PathBuf,
+        sort: Sort,
+        flatten: bool,
+    ) -> Result<Paths, Box<dyn Error>> {
+        let reader: Box<dyn BufRead> = if list == Path::new("-") {
+            Box::new(io::stdin().lock())
+        } else {
+            let file = File::open(list)
+                .map_err(|error| format!("Can't read the list {}: {}", list.display(), error))?;
+            Box::new(BufReader::new(file))
         };
 
-        let mut all_files: Vec<PathBuf> = Vec::new();
-        Self::flatten_dir(input_path.clone(), &mut all_files, depth);
+        let mut images = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let path = std::path::absolute(line)?;
+            if path.is_file() {
+                images.push(path);
+            } else {
+                eprintln!("{} is not a file, skipping it", line);
+            }
+        }
+
+        Ok(Self::new(
+            std::path::absolute(root)?,
+            images,
+            output_path,
+            sort,
+            flatten,
+        ))
+    }
+
+    fn new(
+        root: PathBuf,
+        mut images: Vec<PathBuf>,
+        output_path: PathBuf,
+        sort: Sort,
+        flatten: bool,
+    ) -> Paths {
+        sort_paths(&mut images, sort);
+        let colliding = colliding_stems(&images, flatten);
 
         Self {
             input: InputPaths {
-                root: input_path,
-                images: all_files,
+                root,
+                images,
+                colliding,
+                unreadable: Vec::new(),
             },
             output_root: output_path,
         }
     }
 
-    /// Returns (input_size, output_size)
-    fn flatten_dir(input_path: PathBuf, all_files: &mut Vec<PathBuf>, depth: Depth) {
+    /// Walks subdirectories in parallel on the rayon pool, which matters on
+    /// network drives where every `read_dir` is a round trip
+    fn flatten_dir(
+        input_path: PathBuf,
+        walk_options: &WalkOptions,
+        send: &(dyn Fn(PathBuf) + Sync),
+        fail: &(dyn Fn(&Path, io::Error) + Sync),
+        stopped: &(dyn Fn() -> bool + Sync),
+        depth: Depth,
+    ) {
+        if stopped() {
+            return;
+        }
+        // The input itself is taken whatever its name. `.` and `..` have no
+        // file name, so they're never mistaken for hidden
+        let name = input_path.file_name().filter(|_| depth.current > 0);
         if input_path.is_file() {
-            all_files.push(input_path.clone());
+            if !name.is_some_and(|name| walk_options.excludes_file(name)) {
+                send(input_path);
+            }
             return;
         }
-        if input_path.is_dir() {
-            for path in input_path.read_dir().unwrap() {
-                if path.is_err() || depth.current + 1 > depth.max {
-                    return;
+        if !input_path.is_dir() || depth.current > depth.max {
+            return;
+        }
+        if name.is_some_and(|name| walk_options.excludes_dir(name)) {
+            return;
+        }
+
+        let new_depth = Depth {
+            current: depth.current + 1,
+            max: depth.max,
+        };
+        let entries = match input_path.read_dir() {
+            Ok(entries) => entries,
+            Err(error) => return fail(&input_path, error),
+        };
+        entries
+            .take_while(|_| !stopped())
+            .par_bridge()
+            .for_each(|entry| match entry {
+                Ok(entry) => {
+                    Self::flatten_dir(entry.path(), walk_options, send, fail, stopped, new_depth)
+                }
+                Err(error) => fail(&input_path, error),
+            });
+    }
+}
+
+/// Sends every file under `input_paths` as soon as it's found, so conversion
+/// can start before the walk is done. Returns once the walk is complete, or
+/// as soon as `stopped` returns true. Files under several of the inputs are
+/// only sent once. Directories that can't be read are passed to `fail` and
+/// left out
+fn walk(
+    input_paths: &[PathBuf],
+    walk_options: &WalkOptions,
+    sender: Sender<PathBuf>,
+    fail: &(dyn Fn(&Path, io::Error) + Sync),
+    stopped: &(dyn Fn() -> bool + Sync),
+) {
+    let depth = Depth {
+        current: 0,
+        max: walk_options.max_depth,
+    };
+    let sent = Mutex::new(HashSet::new());
+    let send = |path: PathBuf| {
+        if input_paths.len() == 1 || sent.lock().unwrap().insert(path.clone()) {
+            let _ = sender.send(path);
+        }
+    };
+    for input_path in input_paths {
+        let _phase = profile::phase("walk", input_path);
+        Paths::flatten_dir(
+            input_path.clone(),
+            walk_options,
+            &send,
+            fail,
+            stopped,
+            depth,
+        );
+    }
+}
+
+/// Runs `walk` in the background and returns the files as they're found.
+/// The walk gets a rayon pool of its own, as the global one is busy
+/// converting and waiting on this very walk. Directories that can't be read
+/// are passed to `fail` as they come up, but an input that can't be read
+/// fails before the walk starts
+pub fn spawn_walk(
+    input_paths: Vec<PathBuf>,
+    walk_options: WalkOptions,
+    fail: impl Fn(&Path, io::Error) + Send + Sync + 'static,
+    stopped: impl Fn() -> bool + Send + Sync + 'static,
+) -> io::Result<Receiver<PathBuf>> {
+    check_readable(&input_paths)?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let pool = ThreadPoolBuilder::new().build().unwrap();
+        pool.install(|| walk(&input_paths, &walk_options, sender, &fail, &stopped));
+    });
+    Ok(receiver)
+}
+
+/// Fails on the first input directory that can't be listed. Directories
+/// further down are only skipped, but a missing input is most likely a mistake
+fn check_readable(input_paths: &[PathBuf]) -> io::Result<()> {
+    for path in input_paths.iter().filter(|path| path.is_dir()) {
+        if let Err(error) = path.read_dir() {
+            return Err(io::Error::new(
+                error.kind(),
+                format!("Can't read {}: {}", path.display(), error),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Deepest path all of `paths` are under, which the output mirrors the
+/// structure from. A lone input is its own root, even if it's a file
+pub fn common_root(paths: &[PathBuf]) -> PathBuf {
+    let [first, rest @ ..] = paths else {
+        return PathBuf::new();
+    };
+    let mut root = first.clone();
+    for path in rest {
+        while !path.starts_with(&root) && root.pop() {}
+    }
+    // The same file given several times
+    if !rest.is_empty() && root.is_file() {
+        root.pop();
+    }
+    root
+}
+
+fn colliding_stems(paths: &[PathBuf], flatten: bool) -> HashSet<PathBuf> {
+    let mut by_stem: HashMap<(Option<&Path>, Option<&OsStr>), Vec<&PathBuf>> = HashMap::new();
+    for path in paths {
+        let parent = if flatten { None } else { path.parent() };
+        by_stem
+            .entry((parent, path.file_stem()))
+            .or_default()
+            .push(path);
+    }
+
+    by_stem
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+fn sort_paths(paths: &mut [PathBuf], sort: Sort) {
+    match sort {
+        Sort::Name => paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy())),
+        Sort::Size => paths.sort_by_cached_key(|path| file_size(path)),
+        Sort::None => {}
+    }
+}
+
+/// Compares strings with runs of digits compared by their numeric value,
+/// so `frame2.png` comes before `frame10.png`
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
                 }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
 
-                let new_depth = Depth {
-                    current: depth.current + 1,
-                    max: depth.max,
-                };
-                Self::flatten_dir(path.unwrap().path(), all_files, new_depth);
+/// Consumes a run of digits, without leading zeros
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        if !(number.is_empty() && c == '0') {
+            number.push(c);
+        }
+    }
+    number
+}
+
+/// `fs::create_dir_all` for directories many threads create at once. Network
+/// file systems can fail it with `AlreadyExists` or `NotFound` when another
+/// thread gets there first, so it only fails if the directory still can't be
+/// created on a second try
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    match fs::create_dir_all(path) {
+        Err(_) if path.is_dir() => Ok(()),
+        Err(_) => fs::create_dir_all(path),
+        created => created,
+    }
+}
+
+/// Runs `operation` again up to `retries` times while it fails with an error
+/// that may go away on its own, like a timeout on a network file system.
+/// Waits 100 ms before the first retry, twice as long before each next one
+pub fn retry<T>(retries: u32, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = Duration::from_millis(100);
+    for _ in 0..retries {
+        match operation() {
+            Err(error) if is_transient(&error) => {
+                thread::sleep(delay);
+                delay *= 2;
             }
+            result => return result,
         }
     }
+    operation()
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Size of the file at `path`, or 0 when it can't be read
+pub fn file_size(path: &Path) -> u64 {
+    path.metadata().map(|metadata| metadata.len()).unwrap_or(0)
 }
@@ -24,5 +24,5 @@ pub fn format_millis(ms: u128) -> String {
         return format!("{:.1} s", seconds);
     }
 
-    return format!("{} min {:.1} s", (seconds / 60.0).floor(), seconds % 60.0);
+    format!("{} min {:.1} s", (seconds / 60.0).floor(), seconds % 60.0)
 }
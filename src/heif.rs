@@ -0,0 +1,67 @@
+use std::path::Path;
+#[cfg(feature = "heif")]
+use std::{error::Error, fs};
+
+#[cfg(feature = "heif")]
+use image::{DynamicImage, RgbImage, RgbaImage};
+#[cfg(feature = "heif")]
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+/// Whether `path` looks like a HEIC or AVIF file, which the `image` crate
+/// can't decode
+pub fn is_heif(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            matches!(
+                extension.to_ascii_lowercase().as_str(),
+                "heic" | "heif" | "avif"
+            )
+        })
+}
+
+/// Decodes the primary image of a HEIC or AVIF file. Images with more than
+/// `max_pixels` pixels are rejected from their header, before being decoded
+#[cfg(feature = "heif")]
+pub fn open(path: &Path, max_pixels: u64) -> Result<DynamicImage, Box<dyn Error>> {
+    let not_decoded = |error: libheif_rs::HeifError| format!("could not be decoded: {}", error);
+    let bytes = fs::read(path).map_err(|error| format!("could not be read: {}", error))?;
+    let context = HeifContext::read_from_bytes(&bytes).map_err(not_decoded)?;
+    let handle = context.primary_image_handle().map_err(not_decoded)?;
+    if handle.width() as u64 * handle.height() as u64 > max_pixels {
+        Err(format!(
+            "has more than {} pixels, raise --max-pixels to convert it",
+            max_pixels
+        ))?
+    }
+    let has_alpha = handle.has_alpha_channel();
+    let (chroma, channels) = if has_alpha {
+        (RgbChroma::Rgba, 4)
+    } else {
+        (RgbChroma::Rgb, 3)
+    };
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(not_decoded)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or("could not be decoded: the decoded image has no interleaved plane")?;
+
+    // Rows can be padded, `stride` is the length of a row in bytes
+    let row_len = plane.width as usize * channels;
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+
+    let img = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    Ok(img.ok_or("could not be decoded: the decoded pixels don't match the image size")?)
+}
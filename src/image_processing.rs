@@ -1,132 +1,1602 @@
-use std::{fs, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    io::{BufReader, Read, Seek},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use image::DynamicImage;
-use libwebp_sys::WebPConfig;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    error::{LimitError, LimitErrorKind},
+    imageops::FilterType,
+    AnimationDecoder, DynamicImage, Frames, GenericImageView, GrayAlphaImage, GrayImage,
+    ImageDecoder, ImageError, ImageFormat, ImageReader, Rgb, RgbImage, RgbaImage,
+};
+use libwebp_sys::{WebPAuxStats, WebPConfig};
+use notify::{EventKind, RecursiveMode, Watcher};
 use rayon::iter::{ParallelBridge, ParallelIterator};
 
-use crate::{args, file_utils, logging::Logging, webp_wrapper};
+use crate::{
+    archive::ZipOutput,
+    args::{self, OutputMode},
+    compare, content_cache, file_utils, format_utils, heif,
+    logging::{FileRecord, ProgressSink, Totals},
+    manifest::Manifest,
+    profile, sidecar, webp_wrapper,
+};
 
-pub fn generate_config(args: &args::Cli) -> WebPConfig {
+/// `image_count` is `None` while the images are still being discovered
+pub fn generate_config(args: &args::Cli, image_count: Option<usize>) -> WebPConfig {
     let mut config: WebPConfig = WebPConfig::new().unwrap();
-    config.lossless = if args.quality == 100 {
-        args.lossless
-    } else {
-        0
+    config.method = args.method() as i32;
+    set_quality(
+        &mut config,
+        args.quality(),
+        args.lossless(),
+        args.lossless_level,
+    );
+    config.preprocessing = args.preprocessing as i32;
+    config.alpha_quality = args.alpha_quality as i32;
+    if args.lossless_alpha {
+        config.alpha_compression = 1;
+        config.alpha_quality = 100;
+    }
+    config.qmin = args.qmin as i32;
+    config.qmax = args.qmax as i32;
+    config.low_memory = args.low_memory as i32;
+    // libwebp can spread a single encode over a few threads of its own. Files
+    // are already spread over the rayon pool, so turning this on for every file
+    // of a batch only oversubscribes the CPU. Only worth it when nothing else runs
+    // in parallel: a single image, or a rayon pool with a single thread.
+    let parallel_files =
+        image_count.is_none_or(|count| count > 1) && rayon::current_num_threads() > 1;
+    config.thread_level = match args.threads_webp {
+        Some(threads_webp) => threads_webp,
+        None => !parallel_files as u8,
     } as i32;
-    config.quality = args.quality as f32;
-    config.method = args.method as i32;
-    // Multi threading
-    config.thread_level = 1;
 
     config
 }
 
+/// Lossless only applies at quality 100, anything lower is lossy.
+/// `lossless_level` then replaces the quality and method with libwebp's preset
+fn set_quality(config: &mut WebPConfig, quality: f32, lossless: bool, lossless_level: Option<u8>) {
+    config.lossless = (quality == 100.0 && lossless) as i32;
+    config.quality = quality;
+    if let Some(level) = lossless_level.filter(|_| config.lossless == 1) {
+        webp_wrapper::lossless_preset(config, level).unwrap();
+    }
+}
+
+/// Per-file behaviour around the encoder itself
+#[derive(Clone)]
+pub struct ConvertOptions {
+    /// Keep the original file when the WebP comes out larger
+    pub use_initial_if_smaller: bool,
+    /// Retry failed lossless encodes as lossy
+    pub fallback_lossy: bool,
+    /// Files smaller than this many bytes are skipped
+    pub min_size: u64,
+    /// Re-encode sources that are already WebP instead of skipping them
+    pub force: bool,
+    /// Copy sources that can't be decoded to the output instead of failing
+    pub copy_unconvertible: bool,
+    /// Convert color images to grayscale before encoding
+    pub grayscale: bool,
+    /// Append `.webp` to the full file name instead of replacing the extension
+    pub keep_original_extension: bool,
+    /// Extension of the outputs, `webp` by default
+    pub output_extension: String,
+    /// Names outputs after a template instead, once the image is decoded
+    pub rename_pattern: Option<args::RenamePattern>,
+    /// Overrides the config's quality for JPEG sources
+    pub quality_jpeg: Option<f32>,
+    /// Overrides the config's quality for PNG sources
+    pub quality_png: Option<f32>,
+    /// Whether quality 100 is lossless, needed again when a per-format
+    /// quality is applied
+    pub lossless: bool,
+    /// `--lossless-level`, for the same reason
+    pub lossless_level: Option<u8>,
+    /// Replaces the config's method with one picked from the image's size
+    pub method_auto: Option<args::MethodAuto>,
+    /// Images with fewer colors than this are encoded losslessly
+    pub palette_threshold: Option<u32>,
+    /// Dithering added to lossy images, from 0 to 100
+    pub dither_strength: u8,
+    /// Search for the highest quality whose output fits in this many bytes
+    pub target_size: Option<u64>,
+    /// Search for the lowest quality whose output reaches this PSNR
+    pub target_psnr: Option<f64>,
+    /// Write the settings used next to each output as JSON
+    pub sidecar: bool,
+    /// Write the encoded image as PNG and its difference with the output
+    /// next to each output
+    pub compare: bool,
+    /// Where outputs are written relative to their source
+    pub output_mode: OutputMode,
+    /// Archive the outputs are written into, instead of the output directory
+    pub archive: Option<Arc<ZipOutput>>,
+    /// Directory of outputs by the hash of their source and settings
+    pub content_cache: Option<PathBuf>,
+    /// Sources converted by previous runs are skipped, the others are added
+    pub manifest: Option<Arc<Manifest>>,
+    /// Frame of animated sources or page of multi-page TIFFs to convert
+    pub frame: Option<usize>,
+    /// Crop images to this rectangle before anything else
+    pub crop: Option<args::Crop>,
+    /// Side of the square thumbnails to make, after cropping
+    pub thumbnail: Option<u32>,
+    /// Let thumbnails be larger than the image they're made from
+    pub allow_upscale: bool,
+    /// Color to composite transparent images over, dropping their alpha
+    pub background: Option<Rgb<u8>>,
+    /// Images with more pixels than this are refused before being decoded
+    pub max_pixels: u64,
+    /// Images whose longer side is shorter than this are skipped
+    pub min_dimension: Option<u32>,
+    /// Images whose longer side is longer than this are skipped
+    pub max_dimension: Option<u32>,
+    /// Stop starting new files once one fails
+    pub abort_on_first_error: bool,
+    /// Print a line with the progress to stderr this often during a batch
+    pub stats_interval: Option<Duration>,
+    /// Break the progress down by the directories right under the input
+    pub progress_per_directory: bool,
+    /// Print libwebp's statistics of each encode
+    pub stats: bool,
+    /// Times a write failing with a transient error is tried again
+    pub retries: u32,
+    /// Files taking longer than this to decode and encode fail
+    pub timeout: Option<Duration>,
+    /// Add the decoder's own error to the message of images that can't be read
+    pub verbose_errors: bool,
+}
+
+impl ConvertOptions {
+    fn quality_for(&self, format: Option<ImageFormat>) -> Option<f32> {
+        match format? {
+            ImageFormat::Jpeg => self.quality_jpeg,
+            ImageFormat::Png => self.quality_png,
+            _ => None,
+        }
+    }
+
+    /// Everything that changes the output of an encode with `config`. Threads
+    /// and memory use don't, so a lone image and a batch share their cache
+    fn settings_fingerprint(&self, config: &WebPConfig) -> String {
+        let mut config = *config;
+        config.thread_level = 0;
+        config.low_memory = 0;
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?} {} {:?} {:?} {:?} {} {:?} {} {} {}",
+            config,
+            self.method_auto,
+            self.quality_jpeg,
+            self.quality_png,
+            // Applied again along with the per-format and palette qualities
+            (self.lossless, self.lossless_level),
+            self.palette_threshold,
+            self.dither_strength,
+            (self.target_size, self.target_psnr),
+            (self.frame, self.crop),
+            self.thumbnail,
+            self.allow_upscale,
+            self.background,
+            self.grayscale,
+            self.fallback_lossy,
+            self.max_pixels,
+        )
+    }
+}
+
+pub fn generate_options(args: &args::Cli) -> ConvertOptions {
+    ConvertOptions {
+        use_initial_if_smaller: args.use_initial_if_smaller,
+        fallback_lossy: args.fallback_lossy,
+        min_size: args.min_size,
+        force: args.force,
+        copy_unconvertible: args.copy_unconvertible,
+        grayscale: args.grayscale,
+        keep_original_extension: args.keep_original_extension,
+        output_extension: args.output_extension.clone(),
+        rename_pattern: args.rename_pattern.clone(),
+        quality_jpeg: args.quality_jpeg,
+        quality_png: args.quality_png(),
+        lossless: args.lossless(),
+        lossless_level: args.lossless_level,
+        method_auto: args.method_auto,
+        palette_threshold: args.palette_threshold,
+        dither_strength: args.dither_strength,
+        target_size: args.target_size,
+        target_psnr: args.target_psnr,
+        sidecar: args.sidecar,
+        compare: args.compare,
+        output_mode: args.output_mode,
+        archive: None,
+        content_cache: args.content_cache.clone(),
+        manifest: None,
+        frame: args.frame,
+        crop: args.crop,
+        thumbnail: args.thumbnail,
+        allow_upscale: args.allow_upscale,
+        background: args.background,
+        max_pixels: args.max_pixels,
+        min_dimension: args.min_dimension,
+        max_dimension: args.max_dimension,
+        abort_on_first_error: args.abort_on_first_error,
+        stats_interval: args.stats_interval.map(Duration::from_secs),
+        progress_per_directory: args.show_progress_per_directory,
+        stats: args.stats,
+        retries: args.retries,
+        timeout: args.timeout.map(Duration::from_secs),
+        verbose_errors: args.verbose_errors,
+    }
+}
+
+/// Totals of the files done so far, updated as each one finishes, so they can
+/// be read from another thread while the batch runs
+#[derive(Default)]
+pub struct RunningTotals {
+    pub input_size: AtomicU64,
+    pub output_size: AtomicU64,
+    pub count: AtomicU64,
+    pub skipped: AtomicU64,
+    /// Set to stop starting new files, the ones in progress still finish
+    pub stopped: AtomicBool,
+    /// Files done and files found so far in each directory right under the
+    /// input, only kept for `--show-progress-per-directory`
+    pub directories: Mutex<BTreeMap<PathBuf, (u64, u64)>>,
+}
+
+impl RunningTotals {
+    fn add(&self, (input_size, output_size, count, skipped): (u64, u64, u64, u64)) {
+        saturating_fetch_add(&self.input_size, input_size);
+        saturating_fetch_add(&self.output_size, output_size);
+        saturating_fetch_add(&self.count, count);
+        saturating_fetch_add(&self.skipped, skipped);
+    }
+
+    /// The totals so far, for a batch that has been running for `duration`
+    pub fn snapshot(&self, duration: Duration) -> Totals {
+        Totals {
+            input_size: self.input_size.load(Ordering::Relaxed),
+            output_size: self.output_size.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            duration,
+        }
+    }
+
+    fn found_in(&self, directory: PathBuf) {
+        self.directories
+            .lock()
+            .unwrap()
+            .entry(directory)
+            .or_default()
+            .1 += 1;
+    }
+
+    fn done_in(&self, directory: PathBuf) {
+        self.directories
+            .lock()
+            .unwrap()
+            .entry(directory)
+            .or_default()
+            .0 += 1;
+    }
+}
+
+/// Adds `value` to `counter`, stopping at `u64::MAX` rather than wrapping
+/// around to a tiny total
+fn saturating_fetch_add(counter: &AtomicU64, value: u64) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+        Some(total.saturating_add(value))
+    });
+}
+
+/// Directory right under `input_root` that `path` is in, for
+/// `--show-progress-per-directory`. Files right under the root, or outside of
+/// it, are grouped as `.`
+fn top_directory(path: &Path, input_root: &Path) -> PathBuf {
+    let mut components = path
+        .strip_prefix(input_root)
+        .unwrap_or(Path::new(""))
+        .components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => PathBuf::from(first.as_os_str()),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Converts `images` as they come, so they can still be being discovered,
+/// mirroring their directories under `input_root` into `output_root`.
+/// `colliding` images keep their original extension in the output name, and
+/// their relative directory too in the flat output mode.
+/// `running` is kept up to date along the way, and no new file is started
+/// once it's stopped
+#[allow(clippy::too_many_arguments)]
 pub fn convert_file_all(
-    paths: file_utils::Paths,
+    images: impl Iterator<Item = PathBuf> + Send,
+    colliding: &HashSet<PathBuf>,
+    input_root: &Path,
+    output_root: &Path,
     config: &WebPConfig,
-    use_initial_if_smaller: u8,
-) -> (u64, u64, u64) {
-    let images = paths.input.images;
-    let input_root = paths.input.root;
-    let output_root = paths.output_root;
-    images
-        .iter()
-        .par_bridge()
-        .map(|path| {
-            let output_path = if path.starts_with(&input_root) {
-                let stripped_path = path.strip_prefix(&input_root).unwrap();
-                &output_root.join(stripped_path)
-            } else {
-                &output_root
+    options: &ConvertOptions,
+    sink: &dyn ProgressSink,
+    running: &RunningTotals,
+) -> Totals {
+    let now = Instant::now();
+    // Output path -> the first source that converts to it
+    let claimed_outputs: Mutex<HashMap<PathBuf, PathBuf>> = Mutex::new(HashMap::new());
+    // Only known when the images aren't being discovered anymore
+    let image_count = images.size_hint().1;
+    // Stops taking images once stopped, rather than going through the rest
+    let images = images.take_while(|_| !running.stopped.load(Ordering::Relaxed));
+    let images = images.inspect(|path| {
+        if options.progress_per_directory {
+            running.found_in(top_directory(path, input_root));
+        }
+    });
+    let (stop_stats, stats_stopped) = mpsc::channel();
+    let (input_size, output_size, count, skipped) = thread::scope(|scope| {
+        if let Some(interval) = options.stats_interval {
+            scope.spawn(move || print_stats_every(interval, running, image_count, stats_stopped));
+        }
+        let file_totals = images
+            .enumerate()
+            .par_bridge()
+            .map(|(index, path)| {
+                if running.stopped.load(Ordering::Relaxed) {
+                    return (path, (0, 0, 0, 0));
+                }
+                if is_skipped(&path, options) || is_done(&path, options) {
+                    sink.on_file_skipped(&path);
+                    return (path, (0, 0, 0, 1));
+                }
+                let is_colliding = colliding.contains(&path);
+                let options = &*options_for(options, is_colliding);
+                let output_base =
+                    output_base_for(&path, input_root, output_root, options, is_colliding);
+                if overwrites_source(&path, &output_base, options) {
+                    eprintln!(
+                        "{} would be overwritten by its own output, skipping it",
+                        path.display()
+                    );
+                    sink.on_file_skipped(&path);
+                    return (path, (0, 0, 0, 1));
+                }
+                // Names from a pattern are only known once the image is decoded
+                if options.rename_pattern.is_none() {
+                    let output_path = webp_output_path(&output_base, options);
+                    match claimed_outputs.lock().unwrap().entry(output_path) {
+                        Entry::Occupied(entry) => eprintln!(
+                            "{} and {} both convert to {}, one will overwrite the other. \
+                         Use --keep-original-extension to keep both",
+                            entry.get().display(),
+                            path.display(),
+                            entry.key().display()
+                        ),
+                        Entry::Vacant(entry) => {
+                            entry.insert(path.clone());
+                        }
+                    }
+                }
+
+                let converted_file =
+                    convert_file_isolated(&path, &output_base, index + 1, config, options, sink);
+                if let Err(error) = &converted_file {
+                    sink.on_file_failed(&path, &error.to_string());
+                    if options.abort_on_first_error {
+                        running.stopped.store(true, Ordering::Relaxed);
+                    }
+                    return (path.clone(), (file_utils::file_size(&path), 0, 1, 0));
+                }
+                let file_totals = (file_utils::file_size(&path), converted_file.unwrap(), 1, 0);
+                (path, file_totals)
+            })
+            .map(|(path, file_totals)| {
+                running.add(file_totals);
+                let (_, _, count, skipped) = file_totals;
+                if options.progress_per_directory && count + skipped > 0 {
+                    running.done_in(top_directory(&path, input_root));
+                }
+                file_totals
+            })
+            .reduce(
+                || (0, 0, 0, 0),
+                |(input_size_0, output_size_0, count_0, skipped_0),
+                 (input_size_1, output_size_1, count_1, skipped_1)| {
+                    (
+                        input_size_0.saturating_add(input_size_1),
+                        output_size_0.saturating_add(output_size_1),
+                        count_0.saturating_add(count_1),
+                        skipped_0.saturating_add(skipped_1),
+                    )
+                },
+            );
+        let _ = stop_stats.send(());
+        file_totals
+    });
+
+    let totals = Totals {
+        input_size,
+        output_size,
+        count,
+        skipped,
+        duration: now.elapsed(),
+    };
+    sink.on_batch_done(&totals);
+    totals
+}
+
+/// Prints each image and where its output would be written, without
+/// converting anything. Images the filters leave out are marked as skipped.
+/// Every problem with the outputs is gathered and reported at the end, rather
+/// than one at a time in the middle of a parallel batch
+pub fn list(
+    paths: &file_utils::Paths,
+    options: &ConvertOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = Vec::new();
+    let mut sources_by_output: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut output_dirs = HashSet::new();
+    for path in &paths.input.images {
+        if is_skipped(path, options) {
+            println!("{} (skipped)", path.display());
+            continue;
+        }
+        let is_colliding = paths.input.colliding.contains(path);
+        let options = &*options_for(options, is_colliding);
+        let output_base = output_base_for(
+            path,
+            &paths.input.root,
+            &paths.output_root,
+            options,
+            is_colliding,
+        );
+        if let Some(dir) = output_base.parent() {
+            output_dirs.insert(dir.to_path_buf());
+        }
+        if options.rename_pattern.is_some() {
+            println!(
+                "{} -> {} (named by --rename-pattern)",
+                path.display(),
+                output_base.with_file_name("").display()
+            );
+            continue;
+        }
+
+        let output_path = webp_output_path(&output_base, options);
+        println!("{} -> {}", path.display(), output_path.display());
+        if is_same_file(path, &output_path) {
+            problems.push(format!("{} would overwrite its own source", path.display()));
+        }
+        if let Some(other) = sources_by_output.insert(output_path.clone(), path) {
+            problems.push(format!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                path.display(),
+                output_path.display()
+            ));
+        }
+    }
+
+    // Archive entries are relative, there's no directory to check for them
+    if !paths.output_root.as_os_str().is_empty() {
+        let unwritable: BTreeSet<String> = output_dirs
+            .iter()
+            .filter_map(|dir| unwritable_reason(dir))
+            .collect();
+        problems.extend(unwritable);
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    eprintln!();
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(format!(
+        "{} problems with the planned outputs",
+        problems.len()
+    ))?
+}
+
+/// Why nothing could be written to `dir`, if anything. Checked on the closest
+/// directory that exists, the rest would be created
+fn unwritable_reason(dir: &Path) -> Option<String> {
+    let (existing, metadata) = dir
+        .ancestors()
+        .find_map(|ancestor| Some((ancestor, fs::metadata(ancestor).ok()?)))?;
+    if !metadata.is_dir() {
+        Some(format!("{} is not a directory", existing.display()))
+    } else if metadata.permissions().readonly() {
+        Some(format!("{} is read-only", existing.display()))
+    } else {
+        None
+    }
+}
+
+/// Prints every setting of `config`, one per line, as libwebp names them
+pub fn print_config(config: &WebPConfig) {
+    let settings: [(&str, &dyn std::fmt::Display); 29] = [
+        ("lossless", &config.lossless),
+        ("quality", &config.quality),
+        ("method", &config.method),
+        ("image_hint", &(config.image_hint as i32)),
+        ("target_size", &config.target_size),
+        ("target_PSNR", &config.target_PSNR),
+        ("segments", &config.segments),
+        ("sns_strength", &config.sns_strength),
+        ("filter_strength", &config.filter_strength),
+        ("filter_sharpness", &config.filter_sharpness),
+        ("filter_type", &config.filter_type),
+        ("autofilter", &config.autofilter),
+        ("alpha_compression", &config.alpha_compression),
+        ("alpha_filtering", &config.alpha_filtering),
+        ("alpha_quality", &config.alpha_quality),
+        ("pass", &config.pass),
+        ("show_compressed", &config.show_compressed),
+        ("preprocessing", &config.preprocessing),
+        ("partitions", &config.partitions),
+        ("partition_limit", &config.partition_limit),
+        ("emulate_jpeg_size", &config.emulate_jpeg_size),
+        ("thread_level", &config.thread_level),
+        ("low_memory", &config.low_memory),
+        ("near_lossless", &config.near_lossless),
+        ("exact", &config.exact),
+        ("use_delta_palette", &config.use_delta_palette),
+        ("use_sharp_yuv", &config.use_sharp_yuv),
+        ("qmin", &config.qmin),
+        ("qmax", &config.qmax),
+    ];
+    for (name, value) in settings {
+        println!("{:<18} {}", name, value);
+    }
+}
+
+/// Colliding images keep their original extension in the output name
+fn options_for(options: &ConvertOptions, is_colliding: bool) -> Cow<'_, ConvertOptions> {
+    if is_colliding {
+        Cow::Owned(ConvertOptions {
+            keep_original_extension: true,
+            ..options.clone()
+        })
+    } else {
+        Cow::Borrowed(options)
+    }
+}
+
+/// Prints the progress so far to stderr every `interval`, until `stop` gets
+/// a message
+fn print_stats_every(
+    interval: Duration,
+    running: &RunningTotals,
+    image_count: Option<usize>,
+    stop: mpsc::Receiver<()>,
+) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        let done = running.count.load(Ordering::Relaxed) + running.skipped.load(Ordering::Relaxed);
+        let saved = running
+            .input_size
+            .load(Ordering::Relaxed)
+            .saturating_sub(running.output_size.load(Ordering::Relaxed));
+        let image_count = image_count.map_or("?".to_string(), |count| count.to_string());
+        eprintln!(
+            "{}/{} files, {} saved",
+            done,
+            image_count,
+            format_utils::format_size(saved)
+        );
+        for (directory, (done, found)) in running.directories.lock().unwrap().iter() {
+            eprintln!("  {}: {}/{}", directory.display(), done, found);
+        }
+    }
+}
+
+/// Encodes `img` lossy at the highest quality whose output fits in
+/// `--target-size`, or the lowest whose output reaches `--target-psnr`, and
+/// leaves that quality in `config`. The qualities from 0 to 100 are bisected,
+/// and when none meets the target the closest end of the range is kept
+fn encode_to_target(
+    img: &DynamicImage,
+    config: &mut WebPConfig,
+    options: &ConvertOptions,
+) -> Result<(webp_wrapper::WebPMemory, Option<WebPAuxStats>), webp_wrapper::EncodeError> {
+    let source = options.target_psnr.map(|_| img.to_rgba8());
+    let (mut low, mut high) = (0_i32, 100_i32);
+    let mut found = None;
+    let mut closest = None;
+    while low <= high {
+        let quality = (low + high) / 2;
+        config.quality = quality as f32;
+        let (webp, stats) = encode_image(img.clone(), config, options)?;
+        let meets_target = match (options.target_size, &source, options.target_psnr) {
+            (Some(size), _, _) => webp.len() as u64 <= size,
+            (None, Some(source), Some(target)) => psnr(source, &webp) >= target,
+            _ => true,
+        };
+        // Higher qualities make larger outputs, and higher PSNRs
+        let try_higher = if options.target_size.is_some() {
+            meets_target
+        } else {
+            !meets_target
+        };
+        if try_higher {
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+        if meets_target {
+            found = Some((quality, webp, stats));
+        } else {
+            closest = Some((quality, webp, stats));
+        }
+    }
+
+    let (quality, webp, stats) = found.or(closest).unwrap();
+    config.quality = quality as f32;
+    Ok((webp, stats))
+}
+
+/// Encodes `img` with the dithering of `options`, along with libwebp's
+/// statistics when they're asked for
+fn encode_image(
+    img: DynamicImage,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+) -> Result<(webp_wrapper::WebPMemory, Option<WebPAuxStats>), webp_wrapper::EncodeError> {
+    let dithering = options.dither_strength as f32 / 100.0;
+    if options.stats {
+        let (webp, stats) = webp_wrapper::image_to_webp_with_stats(img, config, dithering)?;
+        Ok((webp, Some(stats)))
+    } else {
+        Ok((
+            webp_wrapper::image_to_webp_dithered(img, config, dithering)?,
+            None,
+        ))
+    }
+}
+
+/// Prints the fields of `stats` worth tuning with, which differ between lossy
+/// and lossless encodes
+fn print_stats(input: &Path, stats: &WebPAuxStats, lossless: bool) {
+    let name = input.file_name().unwrap_or_default();
+    if lossless {
+        eprintln!(
+            "{:?}: {} B, lossless header {} B and data {} B, palette of {}, cache bits {}",
+            name,
+            stats.coded_size,
+            stats.lossless_hdr_size,
+            stats.lossless_data_size,
+            stats.palette_size,
+            stats.cache_bits
+        );
+    } else {
+        let [y, u, v, all, alpha] = stats.PSNR;
+        eprintln!(
+            "{:?}: {} B, headers {} B, alpha {} B, PSNR Y {:.2} U {:.2} V {:.2} all {:.2} alpha {:.2} dB",
+            name,
+            stats.coded_size,
+            stats.header_bytes[0] + stats.header_bytes[1],
+            stats.alpha_data_size,
+            y,
+            u,
+            v,
+            all,
+            alpha
+        );
+    }
+}
+
+/// Peak signal-to-noise ratio of `webp` against `source` over the color
+/// channels, in dB. Infinite when they're identical
+fn psnr(source: &RgbaImage, webp: &[u8]) -> f64 {
+    let Some(decoded) = webp_wrapper::decode_rgba(webp) else {
+        return 0.0;
+    };
+    let squared_error: u64 = source
+        .pixels()
+        .zip(decoded.pixels())
+        .flat_map(|(a, b)| (0..3).map(move |channel| a[channel].abs_diff(b[channel]) as u64))
+        .map(|difference| difference * difference)
+        .sum();
+    if squared_error == 0 {
+        return f64::INFINITY;
+    }
+    let mean_squared_error = squared_error as f64 / (source.len() / 4 * 3) as f64;
+    10.0 * (255.0 * 255.0 / mean_squared_error).log10()
+}
+
+/// Quality used by `--fallback-lossy` when a lossless encode fails
+const FALLBACK_QUALITY: f32 = 95.0;
+
+/// How long a file has to stay the same size before it's considered fully written
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Converts images created or modified under `input_root` until `running` is
+/// stopped
+pub fn watch(
+    input_root: &Path,
+    output_root: &Path,
+    walk_options: &file_utils::WalkOptions,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+    sink: &dyn ProgressSink,
+    running: &RunningTotals,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(input_root, RecursiveMode::Recursive)?;
+    // Events come with absolute paths, map them back under `input_root`
+    let canonical_input_root = input_root.canonicalize()?;
+
+    // Our own outputs would trigger events too when written inside the input
+    let ignored_root = output_root
+        .starts_with(input_root)
+        .then_some(output_root)
+        .filter(|output_root| *output_root != input_root);
+
+    // Path -> (last seen size, when it last changed)
+    let mut pending: HashMap<PathBuf, (u64, Instant)> = HashMap::new();
+    // Counts on from 1 again, the initial batch is done by then
+    let mut index = 0;
+    while !running.stopped.load(Ordering::Relaxed) {
+        match receiver.recv_timeout(WATCH_SETTLE_TIME / 2) {
+            Ok(Ok(event)) => {
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    continue;
+                }
+                let mut paths = event.paths;
+                while let Some(path) = paths.pop() {
+                    let path = match path.strip_prefix(&canonical_input_root) {
+                        Ok(relative_path) => input_root.join(relative_path),
+                        Err(_) => path,
+                    };
+                    if ignored_root.is_some_and(|root| path.starts_with(root)) {
+                        continue;
+                    }
+                    // Same for outputs written beside their source, which
+                    // `--force` would otherwise convert again
+                    let is_output = path
+                        .extension()
+                        .is_some_and(|extension| *extension == *options.output_extension);
+                    if matches!(options.output_mode, OutputMode::Beside) && is_output {
+                        continue;
+                    }
+                    // Same directories as the initial walk, except for the depth
+                    let relative_path = path.strip_prefix(input_root).unwrap_or(&path);
+                    let directories = if path.is_dir() {
+                        Some(relative_path)
+                    } else {
+                        relative_path.parent()
+                    };
+                    let is_excluded = directories.is_some_and(|directories| {
+                        directories
+                            .components()
+                            .any(|component| walk_options.excludes_dir(component.as_os_str()))
+                    }) || (path.is_file()
+                        && path
+                            .file_name()
+                            .is_some_and(|name| walk_options.excludes_file(name)));
+                    if is_excluded {
+                        continue;
+                    }
+                    // Files copied in along with a new directory can land before
+                    // the directory itself is watched
+                    if path.is_dir() {
+                        if let Ok(entries) = path.read_dir() {
+                            paths.extend(entries.flatten().map(|entry| entry.path()));
+                        }
+                        continue;
+                    }
+                    let size = file_utils::file_size(&path);
+                    pending.insert(path, (size, Instant::now()));
+                }
+            }
+            Ok(Err(error)) => eprintln!("{:?}", error),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        pending.retain(|path, (size, changed_at)| {
+            if changed_at.elapsed() < WATCH_SETTLE_TIME {
+                return true;
+            }
+            let Ok(metadata) = path.metadata() else {
+                return false;
             };
+            if metadata.len() != *size {
+                *size = metadata.len();
+                *changed_at = Instant::now();
+                return true;
+            }
 
-            let converted_file = convert_file(path, output_path, config, use_initial_if_smaller);
-            if converted_file.is_err() {
-                eprintln!("{:?}", converted_file.err());
-                return (path.metadata().unwrap().len(), 0, 1);
+            if is_skipped(path, options) {
+                sink.on_file_skipped(path);
+                return false;
             }
-            return (path.metadata().unwrap().len(), converted_file.unwrap(), 1);
-        })
-        .reduce(
-            || (0, 0, 0),
-            |(input_size_0, output_size_0, count_0), (input_size_1, output_size_1, count_1)| {
-                (
-                    input_size_0 + input_size_1,
-                    output_size_0 + output_size_1,
-                    count_0 + count_1,
-                )
-            },
-        )
+            let output_base = output_base_for(path, input_root, output_root, options, false);
+            if overwrites_source(path, &output_base, options) {
+                eprintln!(
+                    "{} would be overwritten by its own output, skipping it",
+                    path.display()
+                );
+                sink.on_file_skipped(path);
+                return false;
+            }
+            index += 1;
+            let converted_file =
+                convert_file_isolated(path, &output_base, index, config, options, sink);
+            if let Err(error) = converted_file {
+                sink.on_file_failed(path, &error.to_string());
+            }
+            false
+        });
+    }
+    Ok(())
+}
+
+/// Whether `path` is filtered out before being decoded
+fn is_skipped(path: &Path, options: &ConvertOptions) -> bool {
+    let size = file_utils::file_size(path);
+    // Encoding a lossy WebP again only loses more quality
+    size < options.min_size
+        || (!options.force && is_webp(path))
+        || !has_wanted_dimensions(path, options)
 }
 
-/// Returns new file size
+/// Whether the manifest lists `path` as converted by a previous run. Kept out
+/// of `is_skipped` so that `--watch` converts such files again when they change
+fn is_done(path: &Path, options: &ConvertOptions) -> bool {
+    options
+        .manifest
+        .as_ref()
+        .is_some_and(|manifest| manifest.contains(path))
+}
+
+/// Adds `path` to the manifest, once its output is written
+fn record_done(path: &Path, options: &ConvertOptions) {
+    if let Some(manifest) = &options.manifest {
+        let _ = manifest
+            .record(path)
+            .map_err(|error| eprintln!("manifest, {:?}. {}", path, error));
+    }
+}
+
+/// Whether the longer side of the image is within `min_dimension` and
+/// `max_dimension`. Only the header is read, and images without a readable
+/// one are let through to fail with a proper error
+fn has_wanted_dimensions(path: &Path, options: &ConvertOptions) -> bool {
+    if options.min_dimension.is_none() && options.max_dimension.is_none() {
+        return true;
+    }
+    let Ok((width, height)) = image::image_dimensions(path) else {
+        return true;
+    };
+    let longer_side = width.max(height);
+    options.min_dimension.is_none_or(|min| longer_side >= min)
+        && options.max_dimension.is_none_or(|max| longer_side <= max)
+}
+
+/// Whether the WebP of `path` would be written over `path` itself, known
+/// before decoding it. Names from a rename pattern depend on the image, so
+/// `convert_file` checks those once it's encoded
+fn overwrites_source(path: &Path, output_base: &Path, options: &ConvertOptions) -> bool {
+    options.archive.is_none()
+        && options.rename_pattern.is_none()
+        && is_same_file(&webp_output_path(output_base, options), path)
+}
+
+/// Whether both paths lead to the same existing file, whatever the links and
+/// relative components in between
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Checks the RIFF header rather than the extension
+fn is_webp(path: &Path) -> bool {
+    let mut header = [0; 12];
+    let read = fs::File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    read.is_ok() && &header[..4] == b"RIFF" && &header[8..] == b"WEBP"
+}
+
+/// Where `path` would be copied to, which the output is named after
+fn output_base_for(
+    path: &Path,
+    input_root: &Path,
+    output_root: &Path,
+    options: &ConvertOptions,
+    is_colliding: bool,
+) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default();
+    let relative_dir = path
+        .strip_prefix(input_root)
+        .ok()
+        .and_then(|stripped_path| stripped_path.parent())
+        .unwrap_or(Path::new(""));
+    match options.output_mode {
+        OutputMode::Mirror => return output_root.join(relative_dir).join(file_name),
+        OutputMode::Beside => return path.to_path_buf(),
+        OutputMode::Flat if !is_colliding => return output_root.join(file_name),
+        OutputMode::Flat => {}
+    }
+
+    // Named like an image from another directory, so `a/b/logo.png`
+    // becomes `a_b_logo.png`
+    let mut flat_name = OsString::new();
+    for component in relative_dir.components() {
+        flat_name.push(component.as_os_str());
+        flat_name.push("_");
+    }
+    flat_name.push(file_name);
+    output_root.join(flat_name)
+}
+
+/// Where the WebP is written, `logo.webp` or `logo.png.webp` with
+/// `keep_original_extension`, `webp` being the output extension
+fn webp_output_path(output_base: &Path, options: &ConvertOptions) -> PathBuf {
+    let file_name = if options.keep_original_extension {
+        output_base.file_name()
+    } else {
+        output_base.file_stem()
+    };
+    let mut file_name = file_name.unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(&options.output_extension);
+    output_base.with_file_name(file_name)
+}
+
+/// Copies `input` to `output_base` as it is, for `copy_unconvertible`. Returns
+/// the size of the copy
+fn copy_unconvertible(
+    input: &Path,
+    output_base: &Path,
+    options: &ConvertOptions,
+    sink: &dyn ProgressSink,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let now = Instant::now();
+    let input_size = file_utils::file_size(input);
+
+    if let Some(archive) = &options.archive {
+        archive.add(output_base, &fs::read(input)?)?;
+    } else if !is_same_file(output_base, input) {
+        if let Some(output_dir) = output_base.parent() {
+            file_utils::retry(options.retries, || file_utils::create_dir_all(output_dir))?;
+        }
+        file_utils::retry(options.retries, || fs::copy(input, output_base))?;
+    }
+    record_done(input, options);
+
+    sink.on_file_done(&FileRecord {
+        file_name: input
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        source_path: input.to_path_buf(),
+        output_path: output_base.to_path_buf(),
+        used_original: true,
+        input_size,
+        output_size: input_size,
+        previous_output_size: None,
+        dimensions: (0, 0),
+        format: None,
+        effective_quality: None,
+        duration: now.elapsed(),
+    });
+    Ok(input_size)
+}
+
+/// Same as `convert_file`, but a panic in a decoder only fails this file
+/// instead of taking down the whole batch
+fn convert_file_isolated(
+    input: &Path,
+    output_base: &Path,
+    index: usize,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+    sink: &dyn ProgressSink,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        convert_file(input, output_base, index, config, options, sink)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown reason");
+        Err(format!("Panicked while converting: {}", message))?
+    })
+}
+
+/// Returns new file size. `index` is the position of `input` in the batch,
+/// for `{index}` in the rename pattern
 fn convert_file(
-    input: &PathBuf,
-    output: &PathBuf,
+    input: &Path,
+    output_base: &Path,
+    index: usize,
     config: &WebPConfig,
-    use_initial_if_smaller: u8,
+    options: &ConvertOptions,
+    sink: &dyn ProgressSink,
 ) -> Result<u64, Box<dyn std::error::Error>> {
-    let logging = Logging::start_row();
+    let now = Instant::now();
+
+    let file_name = input
+        .file_name()
+        .ok_or_else(|| format!("The file name: {:?} does not exist!", input))?;
 
-    let file_name = &input
-        .file_stem()
-        .ok_or_else(|| format!("The file name: {:?} does not exist!", &input.file_name()))?
-        .to_string_lossy()
-        .to_string();
+    let encoded = match &options.content_cache {
+        Some(cache_dir) => encode_cached(input, cache_dir, config, options),
+        None => encode_with_timeout(input, config, options),
+    };
+    let encoded = match encoded {
+        Err(error) if options.copy_unconvertible && error.is::<NotDecoded>() => {
+            return copy_unconvertible(input, output_base, options, sink);
+        }
+        encoded => encoded?,
+    };
 
-    let img = open_image_from_path(input.clone());
-    if img.is_none() {
-        Err(format!("{:?} is not an image", &input.file_name().unwrap()))?
+    if let (Some(output_dir), None) = (output_base.parent(), &options.archive) {
+        file_utils::retry(options.retries, || file_utils::create_dir_all(output_dir))?;
     }
-    let img = img.unwrap();
+    let output_path = match &options.rename_pattern {
+        _ if encoded.used_original => output_base.to_path_buf(),
+        Some(pattern) => {
+            let (width, height) = encoded.output_dimensions;
+            let values = args::RenameValues {
+                stem: &output_base
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                width,
+                height,
+                quality: encoded.config.quality,
+                index,
+            };
+            output_base.with_file_name(pattern.expand(&values))
+        }
+        None => webp_output_path(output_base, options),
+    };
 
-    let result = webp_wrapper::image_to_webp(img.clone(), &config);
-    let webp = result.map_err(|_| "Failed to convert image")?;
+    let input_size = file_utils::file_size(input);
+    let output_size = encoded.bytes.len() as u64;
 
-    let output_path = if !(&output).exists() {
-        if output.extension().is_some() {
-            fs::create_dir_all(&output.parent().unwrap())?;
-        } else {
-            fs::create_dir_all(&output)?;
+    let mut previous_output_size = None;
+    if let Some(archive) = &options.archive {
+        let _phase = profile::phase("write", input);
+        archive.add(&output_path, &encoded.bytes)?;
+    } else if is_same_file(&output_path, input) {
+        // Keeping the original beside the source means leaving it where it is,
+        // but a new WebP must not replace its own source, like with `--force`.
+        // Only names from a rename pattern get here, `overwrites_source`
+        // catches the rest before they're decoded
+        if !encoded.used_original {
+            Err(format!(
+                "{:?} would be overwritten by its own output, skipping it",
+                file_name
+            ))?
         }
-
-        output
-            .parent()
-            .unwrap()
-            .join(file_name)
-            .with_extension("webp")
     } else {
-        output.join(file_name).with_extension("webp")
+        previous_output_size = output_path.metadata().ok().map(|metadata| metadata.len());
+        let _phase = profile::phase("write", input);
+        file_utils::retry(options.retries, || fs::write(&output_path, &encoded.bytes))?;
+    }
+    record_done(input, options);
+
+    let record = FileRecord {
+        file_name: file_name.to_string_lossy().to_string(),
+        source_path: input.to_path_buf(),
+        output_path,
+        used_original: encoded.used_original,
+        input_size,
+        output_size,
+        previous_output_size,
+        dimensions: encoded.dimensions,
+        format: encoded.format,
+        effective_quality: (options.target_size.is_some() || options.target_psnr.is_some())
+            .then_some(encoded.config.quality),
+        duration: now.elapsed(),
     };
+    if options.sidecar {
+        let _ = sidecar::write(&record, &encoded.config)
+            .map_err(|error| eprintln!("sidecar of {:?}. {}", record.output_path, error));
+    }
+    if let Some(compared_img) = &encoded.compared_img {
+        let _ = compare::write(&record.output_path, compared_img, &encoded.bytes)
+            .map_err(|error| eprintln!("comparison of {:?}. {}", record.output_path, error));
+    }
+    sink.on_file_done(&record);
 
-    let input_size = input.metadata().unwrap().len();
-    let mut output_size = webp.len() as u64;
+    Ok(output_size)
+}
 
-    if use_initial_if_smaller == 1 && input_size < output_size {
-        output_size = input_size;
-        let _ = fs::write(&output_path, img.into_bytes())
-            .map_err(|error| eprintln!("output_path: {:?}. {}", output_path, error));
+pub struct Encoded {
+    /// Bytes to write to the output file
+    pub bytes: Vec<u8>,
+    /// Whether `bytes` are the original file's, which happens when
+    /// `use_initial_if_smaller` is set and the WebP came out larger
+    pub used_original: bool,
+    pub dimensions: (u32, u32),
+    /// Dimensions of the WebP, after cropping and making thumbnails
+    pub output_dimensions: (u32, u32),
+    pub format: Option<ImageFormat>,
+    /// Config the image was encoded with, after the per-format quality and
+    /// the lossy fallback
+    pub config: WebPConfig,
+    /// The image as it was encoded, kept for `compare`
+    pub compared_img: Option<DynamicImage>,
+    /// libwebp's statistics of the encode, with `stats`
+    pub stats: Option<WebPAuxStats>,
+}
+
+/// `encode_with_timeout`, unless the cache has the output of a source with the
+/// same content and settings. Outputs that are a copy of their source aren't
+/// worth caching, and a cached output larger than its source is encoded
+/// again if the source should be kept instead
+fn encode_cached(
+    input: &Path,
+    cache_dir: &Path,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+) -> Result<Encoded, Box<dyn std::error::Error>> {
+    let source = fs::read(input)?;
+    let key = content_cache::key(&source, &options.settings_fingerprint(config));
+    let cached = content_cache::get(cache_dir, &key)
+        .filter(|bytes| !options.use_initial_if_smaller || bytes.len() <= source.len());
+    if let Some(bytes) = cached {
+        if let Some(output_dimensions) = webp_wrapper::dimensions(&bytes) {
+            let format = ImageReader::open(input)?.with_guessed_format()?.format();
+            let mut config = *config;
+            if let Some(quality) = options.quality_for(format) {
+                set_quality(
+                    &mut config,
+                    quality,
+                    options.lossless,
+                    options.lossless_level,
+                );
+            }
+            return Ok(Encoded {
+                bytes,
+                used_original: false,
+                dimensions: image::image_dimensions(input).unwrap_or(output_dimensions),
+                output_dimensions,
+                format,
+                config,
+                compared_img: None,
+                stats: None,
+            });
+        }
+    }
+
+    let encoded = encode_with_timeout(input, config, options)?;
+    if !encoded.used_original {
+        let _ = content_cache::put(cache_dir, &key, &encoded.bytes)
+            .map_err(|error| eprintln!("content cache, {:?}. {}", input, error));
+    }
+    Ok(encoded)
+}
+
+/// A source that couldn't be decoded, told apart from encoding failures for
+/// `copy_unconvertible`
+#[derive(Debug)]
+struct NotDecoded(String);
+
+impl std::fmt::Display for NotDecoded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotDecoded {}
+
+/// Encodes given up on by `encode_with_timeout` that are still running
+static ABANDONED_ENCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// `encode_to_memory`, given up on after `options.timeout`. libwebp can't be
+/// cancelled, so the encode runs on a thread of its own, and one that's given
+/// up on keeps using a core and holding its image until it's done. No more
+/// encodes are started while there are as many of those as threads in the
+/// pool, so a batch of pathological images can't pile them up
+fn encode_with_timeout(
+    input: &Path,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+) -> Result<Encoded, Box<dyn std::error::Error>> {
+    let Some(timeout) = options.timeout else {
+        return encode_to_memory(input, config, options);
+    };
+    let abandoned = ABANDONED_ENCODES.load(Ordering::Relaxed);
+    if abandoned >= rayon::current_num_threads() {
+        Err(format!(
+            "Not started, {} encodes that went past the --timeout are still running",
+            abandoned
+        ))?
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    // Set by whichever of the encode finishing and the wait giving up comes
+    // first, so the other one knows whether the encode counts as abandoned
+    let settled = Arc::new(AtomicBool::new(false));
+    let (input, config, options) = (input.to_path_buf(), *config, options.clone());
+    thread::spawn({
+        let settled = Arc::clone(&settled);
+        move || {
+            let encoded = panic::catch_unwind(AssertUnwindSafe(|| {
+                encode_to_memory(&input, &config, &options)
+            }));
+            if settled.swap(true, Ordering::AcqRel) {
+                ABANDONED_ENCODES.fetch_sub(1, Ordering::Relaxed);
+            }
+            let encoded = encoded.unwrap_or_else(|payload| panic::resume_unwind(payload));
+            // Errors can't be sent between threads, only their message and kind
+            let _ =
+                sender.send(encoded.map_err(|error| (error.to_string(), error.is::<NotDecoded>())));
+        }
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(encoded)) => Ok(encoded),
+        Ok(Err((message, true))) => Err(NotDecoded(message))?,
+        Ok(Err((message, false))) => Err(message)?,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            ABANDONED_ENCODES.fetch_add(1, Ordering::Relaxed);
+            if settled.swap(true, Ordering::AcqRel) {
+                // Finished just as the wait gave up
+                ABANDONED_ENCODES.fetch_sub(1, Ordering::Relaxed);
+            }
+            Err(format!(
+                "Gave up after the {} s --timeout",
+                timeout.as_secs()
+            ))?
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("Panicked while converting")?,
+    }
+}
+
+/// Encodes `input` without writing anything to disk
+pub fn encode_to_memory(
+    input: &Path,
+    config: &WebPConfig,
+    options: &ConvertOptions,
+) -> Result<Encoded, Box<dyn std::error::Error>> {
+    let (mut img, format) = {
+        let _phase = profile::phase("decode", input);
+        open_image_from_path(input.to_path_buf(), options)?
+    };
+    let dimensions = img.dimensions();
+    let mut config = *config;
+    if let Some(quality) = options.quality_for(format) {
+        set_quality(
+            &mut config,
+            quality,
+            options.lossless,
+            options.lossless_level,
+        );
+    }
+    {
+        let _phase = profile::phase("resize", input);
+        if let Some(crop) = options.crop {
+            img = crop_image(&img, crop)?;
+        }
+        if let Some(mut size) = options.thumbnail {
+            if !options.allow_upscale {
+                size = size.min(img.width()).min(img.height());
+            }
+            img = img.resize_to_fill(size, size, FilterType::Lanczos3);
+        }
+        if let Some(background) = options.background.filter(|_| img.color().has_alpha()) {
+            img = composite_over(img, background);
+        }
+        if options.grayscale {
+            img = if img.color().has_alpha() {
+                DynamicImage::ImageLumaA8(img.into_luma_alpha8())
+            } else {
+                DynamicImage::ImageLuma8(img.into_luma8())
+            };
+        }
+    }
+
+    // The lossless preset has a method of its own
+    let has_lossless_preset = config.lossless == 1 && options.lossless_level.is_some();
+    if let Some(method_auto) = options.method_auto.filter(|_| !has_lossless_preset) {
+        config.method = method_auto.method_for(img.width(), img.height()) as i32;
+    }
+
+    if let Some(threshold) = options.palette_threshold {
+        if config.lossless == 0 && has_fewer_colors(&img, threshold) {
+            eprintln!(
+                "{:?}: fewer than {} colors, encoding losslessly",
+                input.file_name().unwrap_or_default(),
+                threshold
+            );
+            config.method = 6;
+            set_quality(&mut config, 100.0, true, options.lossless_level);
+        }
+    }
+
+    let output_dimensions = img.dimensions();
+    let can_fall_back = options.fallback_lossy && config.lossless == 1;
+    let fallback_img = can_fall_back.then(|| img.clone());
+    let compared_img = options.compare.then(|| img.clone());
+    let _encode_phase = profile::phase("encode", input);
+    let has_target = options.target_size.is_some() || options.target_psnr.is_some();
+    // Images the palette threshold made lossless have nothing to search
+    let mut result = if has_target && config.lossless == 0 {
+        encode_to_target(&img, &mut config, options)
     } else {
-        let _ = fs::write(&output_path, &*webp)
-            .map_err(|error| eprintln!("output_path: {:?}. {}", output_path, error));
+        encode_image(img, &config, options)
     };
+    if let (Err(webp_wrapper::EncodeError::Encoding(error)), Some(img)) = (&result, fallback_img) {
+        eprintln!(
+            "{:?}: lossless encoding failed ({:?}), falling back to lossy at quality {}",
+            input.file_name().unwrap_or_default(),
+            error,
+            FALLBACK_QUALITY
+        );
+        config.lossless = 0;
+        config.quality = FALLBACK_QUALITY;
+        result = encode_image(img, &config, options);
+    }
+    let (webp, stats) = result.map_err(|error| format!("Failed to convert image: {}", error))?;
+    if let Some(stats) = &stats {
+        print_stats(input, stats, config.lossless == 1);
+    }
 
-    logging.log_row(
-        input.file_name().unwrap().to_string_lossy().to_string(),
-        input_size,
-        output_size,
-    );
+    let input_size = input.metadata()?.len();
+    let used_original = options.use_initial_if_smaller && input_size < webp.len() as u64;
+    let bytes = if used_original {
+        fs::read(input)?
+    } else {
+        webp.to_vec()
+    };
 
-    Ok(output_size as u64)
+    Ok(Encoded {
+        bytes,
+        used_original,
+        dimensions,
+        output_dimensions,
+        format,
+        config,
+        // Nothing to compare with when the original is kept
+        compared_img: compared_img.filter(|_| !used_original),
+        stats,
+    })
 }
 
-fn open_image_from_path(path: PathBuf) -> Option<DynamicImage> {
-    match image::open(path) {
-        Ok(img) => {
-            return Some(img);
+/// Whether `img` has fewer than `threshold` distinct colors, counting alpha.
+/// Stops counting at `threshold`
+fn has_fewer_colors(img: &DynamicImage, threshold: u32) -> bool {
+    let mut colors = HashSet::new();
+    for pixel in img.to_rgba8().pixels() {
+        colors.insert(pixel.0);
+        if colors.len() >= threshold as usize {
+            return false;
         }
-        Err(_) => {
-            return None;
+    }
+    true
+}
+
+fn crop_image(img: &DynamicImage, crop: args::Crop) -> Result<DynamicImage, String> {
+    let (width, height) = img.dimensions();
+    let fits = crop.x as u64 + crop.width as u64 <= width as u64
+        && crop.y as u64 + crop.height as u64 <= height as u64;
+    if !fits {
+        return Err(format!(
+            "The crop {},{},{},{} doesn't fit in the {}x{} image",
+            crop.x, crop.y, crop.width, crop.height, width, height
+        ));
+    }
+    Ok(img.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+fn composite_over(img: DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    let img = img.into_rgba8();
+    let blend = |color: u8, alpha: u8, background: u8| {
+        let (color, alpha, background) = (color as u32, alpha as u32, background as u32);
+        ((color * alpha + background * (255 - alpha) + 127) / 255) as u8
+    };
+    DynamicImage::ImageRgb8(RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        Rgb([
+            blend(r, a, background[0]),
+            blend(g, a, background[1]),
+            blend(b, a, background[2]),
+        ])
+    }))
+}
+
+/// Returns the image along with its format, detected from its contents
+/// Only files that aren't images, or are in a format that isn't supported,
+/// fail with `NotDecoded`. Images refused by a limit or a `--frame` they
+/// don't have fail like any other error
+fn open_image_from_path(
+    path: PathBuf,
+    options: &ConvertOptions,
+) -> Result<(DynamicImage, Option<ImageFormat>), Box<dyn std::error::Error>> {
+    let file_name = path.file_name().unwrap_or_default().to_owned();
+    let max_pixels = options.max_pixels;
+    let not_an_image = |error: &dyn std::error::Error| {
+        NotDecoded(if options.verbose_errors {
+            format!("{:?} is not an image: {}", file_name, error)
+        } else {
+            format!("{:?} is not an image", file_name)
+        })
+    };
+
+    if heif::is_heif(&path) {
+        if options.frame.is_some_and(|index| index > 0) {
+            Err(format!(
+                "{:?}: only the primary image of a HEIF file can be converted, which is frame 0",
+                file_name
+            ))?
+        }
+        #[cfg(feature = "heif")]
+        return heif::open(&path, max_pixels)
+            .map(|img| (img, None))
+            .map_err(|error| format!("{:?} {}", file_name, error).into());
+        #[cfg(not(feature = "heif"))]
+        Err(format!(
+            "{:?} is a HEIF image, build with `--features heif` to convert it",
+            file_name
+        ))?
+    }
+
+    // The first frame is what the decoders give anyway
+    if let Some(index) = options.frame.filter(|&index| index > 0) {
+        return open_frame(&path, index, max_pixels)
+            .map_err(|error| format!("{:?}: {}", file_name, error).into());
+    }
+
+    let file = fs::File::open(&path).map_err(|error| not_an_image(&error))?;
+    let format_hint = ImageFormat::from_path(&path).ok();
+    let img = open_image_from_reader(file, format_hint, max_pixels).map_err(|error| {
+        let error: Box<dyn std::error::Error> = match error {
+            ImageError::Unsupported(unsupported) => Box::new(NotDecoded(format!(
+                "{:?} is a {} image, which is not supported",
+                file_name,
+                unsupported.format_hint()
+            ))),
+            ImageError::Limits(limit) if limit.kind() == LimitErrorKind::DimensionError => format!(
+                "{:?} has more than {} pixels, raise --max-pixels to convert it",
+                file_name, max_pixels
+            )
+            .into(),
+            ImageError::Limits(limit) => {
+                format!("{:?} is too large to decode: {}", file_name, limit).into()
+            }
+            error => Box::new(not_an_image(&error)),
+        };
+        error
+    })?;
+    Ok(img)
+}
+
+/// Decodes frame `index` of an animated GIF, PNG or WebP, composited like it's
+/// shown, or page `index` of a multi-page TIFF
+fn open_frame(
+    path: &Path,
+    index: usize,
+    max_pixels: u64,
+) -> Result<(DynamicImage, Option<ImageFormat>), Box<dyn std::error::Error>> {
+    let (width, height) = image::image_dimensions(path)?;
+    if width as u64 * height as u64 > max_pixels {
+        Err(format!(
+            "has more than {} pixels, raise --max-pixels to convert it",
+            max_pixels
+        ))?
+    }
+    let format = ImageReader::open(path)?.with_guessed_format()?.format();
+    let file = BufReader::new(fs::File::open(path)?);
+    let frames: Frames = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(file)?.into_frames(),
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(file)?;
+            if !decoder.is_apng()? {
+                Err("a still PNG only has frame 0")?
+            }
+            decoder.apng()?.into_frames()
+        }
+        Some(ImageFormat::WebP) => WebPDecoder::new(file)?.into_frames(),
+        Some(ImageFormat::Tiff) => {
+            return Ok((open_tiff_page(file, index, max_pixels)?, format));
+        }
+        _ => Err("only animated GIF, PNG and WebP and multi-page TIFF have frames after 0")?,
+    };
+
+    let frame = frames
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| format!("there's no frame {}, frames count from 0", index))??;
+    Ok((DynamicImage::ImageRgba8(frame.into_buffer()), format))
+}
+
+/// Decodes page `index` of a TIFF, which the `image` crate can't. Only 8-bit
+/// grayscale and RGB pages, with or without alpha
+fn open_tiff_page<R: Read + Seek>(
+    reader: R,
+    index: usize,
+    max_pixels: u64,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    decoder
+        .seek_to_image(index)
+        .map_err(|_| format!("there's no page {}, pages count from 0", index))?;
+    let (width, height) = decoder.dimensions()?;
+    if width as u64 * height as u64 > max_pixels {
+        Err(format!(
+            "page {} has more than {} pixels, raise --max-pixels to convert it",
+            index, max_pixels
+        ))?
+    }
+    let color_type = decoder.colortype()?;
+    let unsupported = || {
+        format!(
+            "page {} is {:?}, only 8-bit pages are supported",
+            index, color_type
+        )
+    };
+    let tiff::decoder::DecodingResult::U8(pixels) = decoder.read_image()? else {
+        Err(unsupported())?
+    };
+
+    let img = match color_type {
+        tiff::ColorType::Gray(8) => {
+            GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        }
+        tiff::ColorType::GrayA(8) => {
+            GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8)
         }
+        tiff::ColorType::RGB(8) => {
+            RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        tiff::ColorType::RGBA(8) => {
+            RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        }
+        _ => None,
+    };
+    Ok(img.ok_or_else(unsupported)?)
+}
+
+/// Decodes an image from any seekable source, like a file or an in-memory
+/// buffer, along with its format. The format is detected from the contents,
+/// and `format_hint` is only used when they don't give it away.
+/// Images with more than `max_pixels` pixels are rejected from their header,
+/// before anything is allocated for them. `image`'s default allocation limit
+/// still applies on top
+pub fn open_image_from_reader<R: Read + Seek>(
+    reader: R,
+    format_hint: Option<ImageFormat>,
+    max_pixels: u64,
+) -> Result<(DynamicImage, Option<ImageFormat>), ImageError> {
+    let mut reader = ImageReader::new(BufReader::new(reader))
+        .with_guessed_format()
+        .map_err(ImageError::IoError)?;
+    if let (None, Some(format_hint)) = (reader.format(), format_hint) {
+        reader.set_format(format_hint);
+    }
+    let format = reader.format();
+
+    let decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if width as u64 * height as u64 > max_pixels {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
     }
+
+    Ok((DynamicImage::from_decoder(decoder)?, format))
 }
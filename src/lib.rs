@@ -0,0 +1,14 @@
+pub mod archive;
+pub mod args;
+mod compare;
+mod content_cache;
+pub mod file_utils;
+mod format_utils;
+mod heif;
+pub mod image_processing;
+pub mod logging;
+pub mod manifest;
+pub mod profile;
+pub mod report;
+mod sidecar;
+pub mod webp_wrapper;
@@ -1,54 +1,256 @@
-use std::time::Instant;
+use std::{
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use image::ImageFormat;
 
 use crate::format_utils;
 
+/// Result of converting a single file
+pub struct FileRecord {
+    pub file_name: String,
+    pub source_path: PathBuf,
+    pub output_path: PathBuf,
+    /// Whether the original file was kept because the WebP came out larger
+    pub used_original: bool,
+    pub input_size: u64,
+    pub output_size: u64,
+    /// Size of the output this one replaced, left by an earlier run
+    pub previous_output_size: Option<u64>,
+    pub dimensions: (u32, u32),
+    /// Format of the source image, when it could be detected
+    pub format: Option<ImageFormat>,
+    /// Quality a `--target-size` or `--target-psnr` search settled on
+    pub effective_quality: Option<f32>,
+    /// Time spent on this file alone, from decoding to writing the output
+    pub duration: Duration,
+}
+
+/// Result of converting a whole batch
+pub struct Totals {
+    pub input_size: u64,
+    pub output_size: u64,
+    pub count: u64,
+    /// Files left out by the filters, not included in the sizes or `count`
+    pub skipped: u64,
+    /// Wall time of the whole batch, measured once around the parallel
+    /// conversion rather than summed from the per-file durations
+    pub duration: Duration,
+}
+
+/// Receives conversion results as they happen.
+/// Called from the conversion threads, so it has to be `Sync`
+pub trait ProgressSink: Sync {
+    fn on_file_done(&self, record: &FileRecord);
+
+    fn on_file_failed(&self, _path: &Path, _error: &str) {}
+
+    /// Called for files left out by the filters
+    fn on_file_skipped(&self, _path: &Path) {}
+
+    fn on_batch_done(&self, totals: &Totals);
+}
+
+/// Forwards every event to each sink in turn
+impl ProgressSink for Vec<Box<dyn ProgressSink>> {
+    fn on_file_done(&self, record: &FileRecord) {
+        self.iter().for_each(|sink| sink.on_file_done(record));
+    }
+
+    fn on_file_failed(&self, path: &Path, error: &str) {
+        self.iter()
+            .for_each(|sink| sink.on_file_failed(path, error));
+    }
+
+    fn on_file_skipped(&self, path: &Path) {
+        self.iter().for_each(|sink| sink.on_file_skipped(path));
+    }
+
+    fn on_batch_done(&self, totals: &Totals) {
+        self.iter().for_each(|sink| sink.on_batch_done(totals));
+    }
+}
+
+/// Prints results as a table to stdout
 pub struct Logging {
-    now: Instant,
+    /// Adds the dimensions and format of each image to the table
+    verbose: bool,
+    /// Prints only the totals, without the header and a row per file
+    summary_only: bool,
+    /// Colors the savings, only when printing to a terminal
+    color: bool,
+    /// Adds the quality a target search settled on to the table
+    effective_quality: bool,
+    /// Name, input size and output size of every file, for the spread of
+    /// sizes and savings printed with the totals
+    files: Mutex<Vec<(String, u64, u64)>>,
 }
 
 impl Logging {
-    pub fn start() -> Self {
-        println!(
-            "{0:<30} | {1:<10} | {2:<10} | {3:<10}",
-            "Name", "Input", "Output", "Duration"
-        );
+    pub fn start(verbose: bool, summary_only: bool, effective_quality: bool) -> Self {
+        if !summary_only {
+            let mut header = format!(
+                "{0:<30} | {1:<10} | {2:<10} | {3:<10} | {4:<8} | {5:<10}",
+                "Name", "Input", "Output", "Previous", "Saved", "Duration"
+            );
+            if effective_quality {
+                header += &format!(" | {0:<11}", "Effective Q");
+            }
+            if verbose {
+                header += &format!(" | {0:<11} | {1:<6}", "Dimensions", "Format");
+            }
+            println!("{}", header);
+        }
 
         Self {
-            now: Instant::now(),
+            verbose,
+            summary_only,
+            color: io::stdout().is_terminal(),
+            effective_quality,
+            files: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn start_row() -> Self {
-        Self {
-            now: Instant::now(),
+    /// Prints the smallest, median and largest output, and the files that
+    /// saved the most and the least, to spot outliers
+    fn print_spread(&self) {
+        let files = self.files.lock().unwrap();
+        if files.is_empty() {
+            return;
+        }
+        let mut output_sizes: Vec<u64> = files.iter().map(|(_, _, output)| *output).collect();
+        output_sizes.sort_unstable();
+        println!(
+            "Output sizes: {} smallest, {} median, {} largest",
+            format_utils::format_size(output_sizes[0]),
+            format_utils::format_size(output_sizes[output_sizes.len() / 2]),
+            format_utils::format_size(output_sizes[output_sizes.len() - 1])
+        );
+
+        let saved = |(_, input, output): &&(String, u64, u64)| saved_percentage(*input, *output);
+        let best = files.iter().max_by(|a, b| saved(a).total_cmp(&saved(b)));
+        let worst = files.iter().min_by(|a, b| saved(a).total_cmp(&saved(b)));
+        if let (Some(best), Some(worst)) = (best, worst) {
+            println!("Most saved: {} with {:.1} %", best.0, saved(&best));
+            println!("Least saved: {} with {:.1} %", worst.0, saved(&worst));
         }
     }
+}
 
-    pub fn log_row(&self, input_file_name: String, input_size: u64, output_size: u64) {
-        println!(
-            "{0:<30} | {1:<10} | {2:<10} | {3:<10}",
-            input_file_name,
-            format_utils::format_size(input_size),
-            format_utils::format_size(output_size),
-            format_utils::format_millis(self.now.elapsed().as_millis())
+impl ProgressSink for Logging {
+    fn on_file_done(&self, record: &FileRecord) {
+        self.files.lock().unwrap().push((
+            record.file_name.clone(),
+            record.input_size,
+            record.output_size,
+        ));
+        if self.summary_only {
+            return;
+        }
+        let saved = saved_percentage(record.input_size, record.output_size);
+        // Padded before coloring, the escape codes don't take any room
+        let mut saved_column = format!("{:<8}", format!("{:.1} %", saved));
+        if self.color {
+            // Red for files that grew, worth a look with --use-initial-if-smaller
+            let color = if record.output_size > record.input_size {
+                "31"
+            } else {
+                "32"
+            };
+            saved_column = format!("\x1b[{}m{}\x1b[0m", color, saved_column);
+        }
+        // What an earlier run produced, to see whether new settings helped
+        let previous = match record.previous_output_size {
+            Some(size) => format_utils::format_size(size),
+            None => "-".to_string(),
+        };
+        let mut row = format!(
+            "{0:<30} | {1:<10} | {2:<10} | {3:<10} | {4} | {5:<10}",
+            record.file_name,
+            format_utils::format_size(record.input_size),
+            format_utils::format_size(record.output_size),
+            previous,
+            saved_column,
+            format_utils::format_millis(record.duration.as_millis())
         );
+        if self.effective_quality {
+            let quality = match record.effective_quality {
+                Some(quality) => quality.to_string(),
+                None => "-".to_string(),
+            };
+            row += &format!(" | {0:<11}", quality);
+        }
+        if self.verbose {
+            let (width, height) = record.dimensions;
+            let format = match record.format {
+                Some(format) => format!("{:?}", format),
+                None => "?".to_string(),
+            };
+            row += &format!(
+                " | {0:<11} | {1:<6}",
+                format!("{}x{}", width, height),
+                format
+            );
+        }
+        println!("{}", row);
+    }
+
+    fn on_file_failed(&self, path: &Path, error: &str) {
+        eprintln!("{}: {}", path.display(), error);
     }
 
-    pub fn end(&self, input_size: u64, output_size: u64, count: u64) {
-        println!("\n--- TOTAL --- ");
+    fn on_batch_done(&self, totals: &Totals) {
+        if !self.summary_only {
+            println!();
+        }
+        if totals.count == 0 {
+            match totals.skipped {
+                0 => println!("No images found"),
+                skipped => println!("No images converted, {} skipped", skipped),
+            }
+            return;
+        }
+        println!("--- TOTAL --- ");
         println!(
-            "{0:<12} | {1:<12} | {2:<12} | {3:<12} | {4:<12}",
-            "Input Size", "Output Size", "Reduction", "Duration", "Images Count"
+            "{0:<12} | {1:<12} | {2:<12} | {3:<12} | {4:<12} | {5:<12} | {6:<12} | {7:<12}",
+            "Input Size",
+            "Output Size",
+            "Reduction",
+            "Duration",
+            "Images Count",
+            "Skipped",
+            "Throughput",
+            "Images/s"
         );
-        let reduction_difference = input_size as f64 - output_size as f64;
-        let reduction_percentage = 100.0 * reduction_difference / input_size as f64;
+        let reduction_percentage = saved_percentage(totals.input_size, totals.output_size);
+        // Input bytes read per second, the same figure whatever the settings
+        let seconds = totals.duration.as_secs_f64().max(f64::EPSILON);
+        let throughput = (totals.input_size as f64 / seconds) as u64;
+        let images_per_second = totals.count as f64 / seconds;
         println!(
-            "{0:<12} | {1:<12} | {2:<12} | {3:<12} | {4:<12}",
-            format_utils::format_size(input_size),
-            format_utils::format_size(output_size),
+            "{0:<12} | {1:<12} | {2:<12} | {3:<12} | {4:<12} | {5:<12} | {6:<12} | {7:<12}",
+            format_utils::format_size(totals.input_size),
+            format_utils::format_size(totals.output_size),
             format!("{:.1?} %", reduction_percentage),
-            format_utils::format_millis(self.now.elapsed().as_millis()),
-            count
+            format_utils::format_millis(totals.duration.as_millis()),
+            totals.count,
+            totals.skipped,
+            format!("{}/s", format_utils::format_size(throughput)),
+            format!("{:.1}", images_per_second)
         );
+        self.print_spread();
+    }
+}
+
+/// Share of `input` saved in percent, negative when the output is larger.
+/// Empty files still count as images, so the input can be 0 bytes
+pub fn saved_percentage(input: u64, output: u64) -> f64 {
+    if input == 0 {
+        0.0
+    } else {
+        100.0 * (input as f64 - output as f64) / input as f64
     }
 }
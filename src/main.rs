@@ -1,40 +1,254 @@
 use clap::Parser;
-use logging::Logging;
-use std::path::PathBuf;
-
-mod args;
-mod file_utils;
-mod format_utils;
-mod image_processing;
-mod logging;
-mod webp_wrapper;
-
-struct Depth {
-    current: u16,
-    max: u16,
-}
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use webp::{
+    archive,
+    args::{self, OutputMode, Sort},
+    file_utils, image_processing,
+    logging::{Logging, ProgressSink},
+    manifest::Manifest,
+    profile, report, webp_wrapper,
+};
 
 fn main() {
-    let result = try_main();
-    if result.is_err() {
-        eprintln!("{:#?}", result.err());
+    if let Err(error) = try_main() {
+        eprintln!("{}", error);
+        std::process::exit(1);
     }
 }
 
 fn try_main() -> Result<(), Box<dyn std::error::Error>> {
     let args = args::Cli::parse();
 
-    let output_path: PathBuf = args.output_path().map_err(|error| error).unwrap();
-    let input_path: PathBuf = args.input_path().map_err(|error| error).unwrap();
-    let config = image_processing::generate_config(&args);
+    let mut input_paths: Vec<PathBuf> = args.input_paths()?;
+    let output_path: PathBuf = args.output_path(&input_paths)?;
+    let input_path = match &args.keep_structure_from {
+        // Images are matched against the root, so both have to be absolute
+        Some(root) => {
+            input_paths = input_paths
+                .iter()
+                .map(std::path::absolute)
+                .collect::<Result<_, _>>()?;
+            std::path::absolute(root)?
+        }
+        None => file_utils::common_root(&input_paths),
+    };
+    if input_paths.len() > 1 {
+        if args.watch {
+            Err("--watch takes a single input")?
+        }
+        if args.files_from.is_some() {
+            Err("--files-from takes a single input, the directory mirrored in the output")?
+        }
+    }
+    let archive_path = archive::is_archive(&output_path).then_some(output_path.clone());
+    if archive_path.is_some() {
+        if args.watch {
+            Err("--watch can't be used with a zip output, which is only readable once finished")?
+        }
+        if args.sidecar || args.compare {
+            Err("--sidecar and --compare can't be used with a zip output")?
+        }
+        if args.manifest.is_some() {
+            Err("--manifest can't be used with a zip output, which is written anew every run")?
+        }
+    }
+    // Archive entries are named relative to the root of the archive
+    let output_path = if archive_path.is_some() {
+        PathBuf::new()
+    } else {
+        output_path
+    };
 
-    let logging = Logging::start();
+    if args.verbose {
+        let (major, minor, revision) = webp_wrapper::encoder_version();
+        println!(
+            "libwebp {}.{}.{} ({})",
+            major,
+            minor,
+            revision,
+            webp_wrapper::LINKING
+        );
+    }
 
-    let paths = file_utils::Paths::build(input_path, output_path, args.max_depth);
-    let (input_size, output_size, count) =
-        image_processing::convert_file_all(paths, &config, args.use_initial_if_smaller);
+    let mut options = image_processing::generate_options(&args);
+    let walk_options = file_utils::generate_walk_options(&args);
+    // Collisions can only be avoided when all the files are known up front,
+    // and flattening makes them far more likely
+    let flatten = matches!(args.output_mode, OutputMode::Flat);
+    let running = Arc::new(image_processing::RunningTotals::default());
+    let build_paths = || -> Result<file_utils::Paths, Box<dyn std::error::Error>> {
+        Ok(match &args.files_from {
+            Some(list) => file_utils::Paths::from_list(
+                list,
+                input_path.clone(),
+                output_path.clone(),
+                args.sort,
+                flatten,
+            )?,
+            None => {
+                let mut paths = file_utils::Paths::build(
+                    input_paths.clone(),
+                    output_path.clone(),
+                    &walk_options,
+                    args.sort,
+                    flatten,
+                    &|| running.stopped.load(Ordering::Relaxed),
+                )?;
+                for (path, error) in &paths.input.unreadable {
+                    eprintln!("Can't read {}, skipping it: {}", path.display(), error);
+                }
+                paths.input.root = input_path.clone();
+                paths
+            }
+        })
+    };
+    if args.list {
+        image_processing::list(&build_paths()?, &options)?;
+        return Ok(());
+    }
+    for warning in args.warnings() {
+        eprintln!("{}", warning);
+    }
+    if args.print_config {
+        // Set up as for a batch, a lone image may get libwebp's threads too
+        let config = image_processing::generate_config(&args, None);
+        image_processing::print_config(&config);
+        webp_wrapper::validate_config(&config)?;
+        return Ok(());
+    }
 
-    logging.end(input_size, output_size, count);
+    if let Some(profile_path) = &args.profile {
+        profile::start(profile_path)?;
+    }
+    let mut sinks: Vec<Box<dyn ProgressSink>> = Vec::new();
+    if let Some(report_path) = &args.report {
+        sinks.push(Box::new(report::CsvReport::create(report_path)?));
+    }
+    sinks.push(Box::new(Logging::start(
+        args.verbose,
+        args.summary_only,
+        args.has_target(),
+    )));
+
+    if let Some(archive_path) = &archive_path {
+        options.archive = Some(Arc::new(archive::ZipOutput::create(archive_path)?));
+    }
+    if let Some(manifest_path) = &args.manifest {
+        let manifest = Manifest::open(manifest_path).map_err(|error| {
+            format!(
+                "Can't open the manifest {}: {}",
+                manifest_path.display(),
+                error
+            )
+        })?;
+        options.manifest = Some(Arc::new(manifest));
+    }
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let running = Arc::clone(&running);
+        let interrupted = Arc::clone(&interrupted);
+        move || {
+            if interrupted.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+            // The totals are printed once the images in progress are done
+            running.stopped.store(true, Ordering::Relaxed);
+            eprintln!(
+                "Interrupted, finishing the images in progress. Press Ctrl-C again to quit now"
+            );
+        }
+    })?;
+    let stream = matches!(args.sort, Sort::None) && args.files_from.is_none() && !flatten;
+    let config = if stream {
+        // No order to respect, so convert files while the walk finds them
+        let images = file_utils::spawn_walk(
+            input_paths.clone(),
+            walk_options.clone(),
+            |path, error| eprintln!("Can't read {}, skipping it: {}", path.display(), error),
+            {
+                let running = Arc::clone(&running);
+                move || running.stopped.load(Ordering::Relaxed)
+            },
+        )?;
+        // Waiting for a second image tells a lone image, which gets libwebp's
+        // own threads, from a batch
+        let first_images: Vec<PathBuf> = images.iter().take(2).collect();
+        let image_count = (first_images.len() < 2).then_some(first_images.len());
+        let config = image_processing::generate_config(&args, image_count);
+        webp_wrapper::validate_config(&config)?;
+        image_processing::convert_file_all(
+            first_images.into_iter().chain(images),
+            &HashSet::new(),
+            &input_path,
+            &output_path,
+            &config,
+            &options,
+            &sinks,
+            &running,
+        );
+        config
+    } else {
+        let paths = build_paths()?;
+        let mut colliding: Vec<_> = paths.input.colliding.iter().collect();
+        if !colliding.is_empty() && (flatten || !args.keep_original_extension) {
+            colliding.sort();
+            if flatten {
+                eprintln!(
+                    "These images share a name, so their outputs keep their directory and extension:"
+                );
+            } else {
+                eprintln!(
+                    "These images share a name, so their outputs keep the original extension:"
+                );
+            }
+            for path in colliding {
+                eprintln!("  {}", path.display());
+            }
+        }
+        let image_count = paths.input.images.len();
+        let config = image_processing::generate_config(&args, Some(image_count));
+        webp_wrapper::validate_config(&config)?;
+        image_processing::convert_file_all(
+            paths.input.images.into_iter(),
+            &paths.input.colliding,
+            &paths.input.root,
+            &paths.output_root,
+            &config,
+            &options,
+            &sinks,
+            &running,
+        );
+        config
+    };
+    if let Some(archive) = &options.archive {
+        archive.finish()?;
+    }
+    if interrupted.load(Ordering::Relaxed) {
+        Err("Interrupted")?
+    }
+    if running.stopped.load(Ordering::Relaxed) {
+        Err("Stopped after the first error")?
+    }
+
+    if args.watch {
+        println!("\nWatching {} for new images...", input_path.display());
+        image_processing::watch(
+            &input_path,
+            &output_path,
+            &walk_options,
+            &config,
+            &options,
+            &sinks,
+            &running,
+        )?;
+    }
 
     Ok(())
 }
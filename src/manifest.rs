@@ -0,0 +1,54 @@
+use std::{
+    collections::HashSet,
+    env,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Append-only list of the sources converted so far, one absolute path per
+/// line, so an interrupted run can resume where it stopped
+pub struct Manifest {
+    /// Sources listed when the manifest was opened
+    done: HashSet<PathBuf>,
+    writer: Mutex<File>,
+}
+
+impl Manifest {
+    /// Opens the manifest at `path`, creating it if needed. A new manifest
+    /// starts with a `#` line holding the command it was made with
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut done = HashSet::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if !line.is_empty() && !line.starts_with('#') {
+                    done.insert(PathBuf::from(line));
+                }
+            }
+        }
+
+        let mut writer = OpenOptions::new().create(true).append(true).open(path)?;
+        if writer.metadata()?.len() == 0 {
+            let command: Vec<String> = env::args().collect();
+            writeln!(writer, "# {}", command.join(" "))?;
+        }
+        Ok(Self {
+            done,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Whether a previous run already converted `source`
+    pub fn contains(&self, source: &Path) -> bool {
+        std::path::absolute(source).is_ok_and(|source| self.done.contains(&source))
+    }
+
+    /// Appends `source`, written straight to the file so it's kept even if the
+    /// run is killed right after
+    pub fn record(&self, source: &Path) -> io::Result<()> {
+        let line = format!("{}\n", std::path::absolute(source)?.display());
+        self.writer.lock().unwrap().write_all(line.as_bytes())
+    }
+}
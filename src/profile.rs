@@ -0,0 +1,135 @@
+use std::path::Path;
+#[cfg(feature = "profile")]
+use std::{error::Error, fmt::Debug, fs::File, sync::Mutex, time::Instant};
+
+#[cfg(feature = "profile")]
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, EnteredSpan, Id},
+    Subscriber,
+};
+#[cfg(feature = "profile")]
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+/// Guard of a phase being timed, which ends when dropped
+#[cfg(feature = "profile")]
+pub type Phase = EnteredSpan;
+#[cfg(not(feature = "profile"))]
+pub struct Phase;
+
+/// Starts timing `name`, one of walk, decode, resize, encode or write, for
+/// `path`. Only recorded with `--profile`, and nothing at all without the
+/// `profile` feature
+#[cfg(feature = "profile")]
+pub fn phase(name: &'static str, path: &Path) -> Phase {
+    tracing::info_span!("phase", name, path = %path.display()).entered()
+}
+
+#[cfg(not(feature = "profile"))]
+pub fn phase(_name: &'static str, _path: &Path) -> Phase {
+    Phase
+}
+
+/// Writes a CSV row to `path` for every phase as it ends, with when it
+/// started and how long it took in microseconds
+#[cfg(feature = "profile")]
+pub fn start(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(path)?);
+    writer.write_record(["phase", "path", "thread", "start_us", "duration_us"])?;
+    let layer = TimingLayer {
+        writer: Mutex::new(writer),
+        started: Instant::now(),
+    };
+    tracing::subscriber::set_global_default(Registry::default().with(layer))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "profile"))]
+pub fn start(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--profile needs a build with `--features profile`")?
+}
+
+#[cfg(feature = "profile")]
+struct TimingLayer {
+    writer: Mutex<csv::Writer<File>>,
+    started: Instant,
+}
+
+/// Kept on each span until it closes
+#[cfg(feature = "profile")]
+struct Timing {
+    fields: Fields,
+    thread: String,
+    started: Instant,
+}
+
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct Fields {
+    name: String,
+    path: String,
+}
+
+#[cfg(feature = "profile")]
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "name" => self.name = value.to_string(),
+            "path" => self.path = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+#[cfg(feature = "profile")]
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TimingLayer {
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, context: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        // Like `ThreadId(3)`, the pool's threads have no name
+        let thread = format!("{:?}", std::thread::current().id());
+        let timing = Timing {
+            fields,
+            thread: thread
+                .trim_start_matches("ThreadId(")
+                .trim_end_matches(')')
+                .to_string(),
+            started: Instant::now(),
+        };
+        if let Some(span) = context.span(id) {
+            span.extensions_mut().insert(timing);
+        }
+    }
+
+    fn on_close(&self, id: Id, context: Context<'_, S>) {
+        let Some(span) = context.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+        let start = timing.started.duration_since(self.started).as_micros();
+        let duration = timing.started.elapsed().as_micros();
+
+        // Written at once, so a run that's killed still leaves a usable report
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writer
+            .write_record([
+                &timing.fields.name,
+                &timing.fields.path,
+                &timing.thread,
+                &start.to_string(),
+                &duration.to_string(),
+            ])
+            .and_then(|_| Ok(writer.flush()?));
+    }
+}
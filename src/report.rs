@@ -0,0 +1,90 @@
+use std::{fs::File, path::Path, sync::Mutex};
+
+use crate::{
+    file_utils,
+    logging::{saved_percentage, FileRecord, ProgressSink, Totals},
+};
+
+/// Writes a CSV row for every file, for `--report`
+pub struct CsvReport {
+    writer: Mutex<csv::Writer<File>>,
+}
+
+impl CsvReport {
+    pub fn create(path: &Path) -> Result<Self, csv::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record([
+            "source_path",
+            "output_path",
+            "input_bytes",
+            "output_bytes",
+            "reduction_pct",
+            "duration_ms",
+            "status",
+        ])?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn write_row(&self, row: [String; 7]) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(error) = writer.write_record(&row) {
+            eprintln!("Failed to write the report: {}", error);
+        }
+    }
+}
+
+impl ProgressSink for CsvReport {
+    fn on_file_done(&self, record: &FileRecord) {
+        let reduction_percentage = saved_percentage(record.input_size, record.output_size);
+        let status = if record.used_original {
+            "kept_original"
+        } else {
+            "converted"
+        };
+
+        self.write_row([
+            record.source_path.display().to_string(),
+            record.output_path.display().to_string(),
+            record.input_size.to_string(),
+            record.output_size.to_string(),
+            format!("{:.1}", reduction_percentage),
+            record.duration.as_millis().to_string(),
+            status.to_string(),
+        ]);
+    }
+
+    fn on_file_failed(&self, path: &Path, error: &str) {
+        let input_size = file_utils::file_size(path);
+        self.write_row([
+            path.display().to_string(),
+            String::new(),
+            input_size.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            format!("failed: {}", error),
+        ]);
+    }
+
+    fn on_file_skipped(&self, path: &Path) {
+        let input_size = file_utils::file_size(path);
+        self.write_row([
+            path.display().to_string(),
+            String::new(),
+            input_size.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            "skipped".to_string(),
+        ]);
+    }
+
+    fn on_batch_done(&self, _totals: &Totals) {
+        if let Err(error) = self.writer.lock().unwrap().flush() {
+            eprintln!("Failed to write the report: {}", error);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::{error::Error, fs, path::Path};
+
+use libwebp_sys::WebPConfig;
+use serde::Serialize;
+
+use crate::{logging::FileRecord, webp_wrapper};
+
+/// Settings and results of a single conversion, to audit or reproduce it later
+#[derive(Serialize)]
+struct Sidecar {
+    /// Version of this tool
+    version: &'static str,
+    /// Version of libwebp the output was encoded with
+    encoder: String,
+    quality: f32,
+    method: i32,
+    lossless: bool,
+    width: u32,
+    height: u32,
+    input_bytes: u64,
+    output_bytes: u64,
+    /// The output is a copy of the source because the WebP came out larger
+    kept_original: bool,
+}
+
+/// Writes the sidecar next to the output, `logo.webp` gets `logo.webp.json`
+pub fn write(record: &FileRecord, config: &WebPConfig) -> Result<(), Box<dyn Error>> {
+    let (width, height) = record.dimensions;
+    let (major, minor, revision) = webp_wrapper::encoder_version();
+    let sidecar = Sidecar {
+        version: env!("CARGO_PKG_VERSION"),
+        encoder: format!("{}.{}.{}", major, minor, revision),
+        quality: config.quality,
+        method: config.method,
+        lossless: config.lossless == 1,
+        width,
+        height,
+        input_bytes: record.input_size,
+        output_bytes: record.output_size,
+        kept_original: record.used_original,
+    };
+
+    let mut path = record.output_path.clone().into_os_string();
+    path.push(".json");
+    fs::write(Path::new(&path), serde_json::to_vec_pretty(&sidecar)?)?;
+    Ok(())
+}
@@ -1,31 +1,596 @@
 use std::{
-    fmt::{Debug, Error, Formatter},
-    ops::{Deref, DerefMut},
+    ffi::c_int,
+    fmt::{Debug, Display, Error, Formatter},
+    io::{self, Write},
+    ops::{Deref, DerefMut, RangeInclusive},
 };
 
-use image::{DynamicImage, GenericImageView};
+use image::{
+    error::{
+        EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind, UnsupportedError,
+        UnsupportedErrorKind,
+    },
+    DynamicImage, ExtendedColorType, GenericImageView, GrayAlphaImage, GrayImage, ImageEncoder,
+    ImageError, ImageFormat, ImageResult, RgbImage, RgbaImage,
+};
 use libwebp_sys::{
-    VP8StatusCode, WebPConfig, WebPEncodingError, WebPFree, WebPMemoryWrite, WebPMemoryWriterInit,
-    WebPPicture, WebPPictureFree, WebPPictureImportRGB, WebPValidateConfig,
+    WebPAuxStats, WebPConfig, WebPConfigLosslessPreset, WebPDecodeRGBA, WebPEncCSP,
+    WebPEncodingError, WebPFree, WebPGetEncoderVersion, WebPGetInfo, WebPMemoryWrite,
+    WebPMemoryWriterInit, WebPPicture, WebPPictureARGBToYUVADithered, WebPPictureAlloc,
+    WebPPictureFree, WebPPictureImportBGRA, WebPPictureImportRGB, WebPPictureImportRGBA,
+    WebPPreset, WebPValidateConfig, WEBP_MAX_DIMENSION,
 };
 
-pub fn image_to_webp(
+#[cfg(all(feature = "static", feature = "system"))]
+compile_error!(
+    "the `static` and `system` features are exclusive, build `system` with `--no-default-features`"
+);
+
+/// Whether libwebp was bundled and linked statically or is the system's,
+/// picked with the `static` and `system` features
+#[cfg(not(feature = "system"))]
+pub const LINKING: &str = "static";
+#[cfg(feature = "system")]
+pub const LINKING: &str = "system";
+
+/// Version of the libwebp encoder linked in, as major, minor and revision
+pub fn encoder_version() -> (u8, u8, u8) {
+    let version = unsafe { WebPGetEncoderVersion() };
+    ((version >> 16) as u8, (version >> 8) as u8, version as u8)
+}
+
+/// Ready-made encoder settings for common kinds of images, so library users
+/// don't need to know libwebp's fields. Derefs to the `WebPConfig` to pass to
+/// `image_to_webp`, which can still be tweaked
+#[derive(Clone, Copy)]
+pub struct EncodeOptions(WebPConfig);
+
+impl EncodeOptions {
+    /// Lossy at quality 80, with libwebp's settings for photos
+    pub fn photo() -> Self {
+        Self::from_preset(WebPPreset::WEBP_PRESET_PHOTO, 80.0)
+    }
+
+    /// Lossless with the smallest files, for screenshots and drawings
+    pub fn lossless() -> Self {
+        let mut config = WebPConfig::new().unwrap();
+        lossless_preset(&mut config, 9).unwrap();
+        Self(config)
+    }
+
+    /// Lossy at quality 90, with libwebp's settings for small images with
+    /// sharp edges. Alpha stays lossless
+    pub fn icon() -> Self {
+        Self::from_preset(WebPPreset::WEBP_PRESET_ICON, 90.0)
+    }
+
+    fn from_preset(preset: WebPPreset, quality: f32) -> Self {
+        Self(WebPConfig::new_with_preset(preset, quality).unwrap())
+    }
+}
+
+impl Deref for EncodeOptions {
+    type Target = WebPConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for EncodeOptions {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Width and height of a WebP image from its header, `None` when it's not a
+/// valid one
+pub fn dimensions(webp: &[u8]) -> Option<(u32, u32)> {
+    let (mut width, mut height): (c_int, c_int) = (0, 0);
+    let is_valid = unsafe { WebPGetInfo(webp.as_ptr(), webp.len(), &mut width, &mut height) };
+    (is_valid != 0).then_some((width as u32, height as u32))
+}
+
+/// Decodes a WebP image to RGBA pixels, `None` when it's not a valid one
+pub fn decode_rgba(webp: &[u8]) -> Option<RgbaImage> {
+    let (mut width, mut height): (c_int, c_int) = (0, 0);
+    unsafe {
+        let pixels = WebPDecodeRGBA(webp.as_ptr(), webp.len(), &mut width, &mut height);
+        if pixels.is_null() {
+            return None;
+        }
+        let rgba =
+            std::slice::from_raw_parts(pixels, width as usize * height as usize * 4).to_vec();
+        WebPFree(pixels as _);
+        RgbaImage::from_raw(width as u32, height as u32, rgba)
+    }
+}
+
+/// Encodes `img`, keeping its alpha channel if it has one
+pub fn image_to_webp(img: DynamicImage, config: &WebPConfig) -> Result<WebPMemory, EncodeError> {
+    image_to_webp_dithered(img, config, 0.0)
+}
+
+/// Encodes `img` like `image_to_webp`, adding `dithering` from 0 to 1 to
+/// lossy images as they're converted to YUV, which hides the banding of
+/// smooth gradients. Lossless images and sharp YUV are left alone
+pub fn image_to_webp_dithered(
     img: DynamicImage,
     config: &WebPConfig,
-) -> Result<WebPMemory, WebPEncodingError> {
+    dithering: f32,
+) -> Result<WebPMemory, EncodeError> {
+    encode_image(img, config, dithering, None)
+}
+
+/// Encodes `img` like `image_to_webp_dithered`, along with libwebp's
+/// statistics of the encode: the size of each part of the output, the PSNR
+/// of each channel for lossy and the transforms picked for lossless
+pub fn image_to_webp_with_stats(
+    img: DynamicImage,
+    config: &WebPConfig,
+    dithering: f32,
+) -> Result<(WebPMemory, WebPAuxStats), EncodeError> {
+    // Plain integers and floats, libwebp fills in the ones that apply
+    let mut stats: WebPAuxStats = unsafe { std::mem::zeroed() };
+    let webp = encode_image(img, config, dithering, Some(&mut stats))?;
+    Ok((webp, stats))
+}
+
+fn encode_image(
+    img: DynamicImage,
+    config: &WebPConfig,
+    dithering: f32,
+    stats: Option<&mut WebPAuxStats>,
+) -> Result<WebPMemory, EncodeError> {
+    check_range("dithering", dithering, 0.0..=1.0)?;
     let (width, height) = img.dimensions();
-    let img = img.into_rgb8();
+    match img {
+        // Lossless works on RGB anyway, so only lossy skips the expansion.
+        // Already YUV, there's no conversion to dither
+        DynamicImage::ImageLuma8(img) if config.lossless == 0 => {
+            encode_pixels(&img, width, height, 1, config, 0.0, stats, new_picture_luma)
+        }
+        img if img.color().has_alpha() => {
+            let rgba = img.into_rgba8();
+            encode_pixels(
+                &rgba,
+                width,
+                height,
+                4,
+                config,
+                dithering,
+                stats,
+                new_picture_rgba,
+            )
+        }
+        img => {
+            let rgb = img.into_rgb8();
+            encode_pixels(
+                &rgb,
+                width,
+                height,
+                3,
+                config,
+                dithering,
+                stats,
+                new_picture,
+            )
+        }
+    }
+}
+
+/// Encodes `img` like `image_to_webp` and writes the WebP to `writer`, like a
+/// socket or a compressor. Encoding errors come back as `io::ErrorKind::Other`
+pub fn encode_to_writer<W: Write>(
+    img: &DynamicImage,
+    config: &WebPConfig,
+    writer: &mut W,
+) -> io::Result<()> {
+    let webp = image_to_webp(img.clone(), config).map_err(io::Error::other)?;
+    writer.write_all(webp.as_slice())
+}
+
+/// `image`'s encoder interface over `image_to_webp`, so WebP can be written
+/// with `img.write_with_encoder(WebPEncoder::new(writer, config))`. Takes 8-bit
+/// grayscale, RGB and RGBA, with or without alpha
+pub struct WebPEncoder<W: Write> {
+    writer: W,
+    config: WebPConfig,
+}
+
+impl<W: Write> WebPEncoder<W> {
+    pub fn new(writer: W, config: WebPConfig) -> Self {
+        Self { writer, config }
+    }
+}
+
+impl<W: Write> ImageEncoder for WebPEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        let pixels = buf.to_vec();
+        let img = match color_type {
+            ExtendedColorType::L8 => {
+                GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+            }
+            ExtendedColorType::La8 => {
+                GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8)
+            }
+            ExtendedColorType::Rgb8 => {
+                RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+            }
+            ExtendedColorType::Rgba8 => {
+                RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+            }
+            color_type => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        ImageFormatHint::Exact(ImageFormat::WebP),
+                        UnsupportedErrorKind::Color(color_type),
+                    ),
+                ))
+            }
+        }
+        .ok_or_else(|| {
+            ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            ))
+        })?;
+
+        let webp = image_to_webp(img, &self.config).map_err(|error| {
+            ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Exact(ImageFormat::WebP),
+                error,
+            ))
+        })?;
+        self.writer.write_all(webp.as_slice())?;
+        Ok(())
+    }
+}
+
+/// Encodes 8-bit grayscale pixels straight into the luma plane, without
+/// expanding them to RGB first
+pub fn encode_luma(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    config: &WebPConfig,
+) -> Result<WebPMemory, EncodeError> {
+    encode_pixels(bytes, width, height, 1, config, 0.0, None, new_picture_luma)
+}
+
+/// Encodes tightly packed 8-bit RGB pixels
+pub fn encode_rgb(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    config: &WebPConfig,
+) -> Result<WebPMemory, EncodeError> {
+    encode_pixels(bytes, width, height, 3, config, 0.0, None, new_picture)
+}
+
+/// Encodes tightly packed 8-bit RGBA pixels
+pub fn encode_rgba(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    config: &WebPConfig,
+) -> Result<WebPMemory, EncodeError> {
+    encode_pixels(bytes, width, height, 4, config, 0.0, None, new_picture_rgba)
+}
+
+/// Encodes tightly packed 8-bit BGRA pixels, the layout of Windows GDI and
+/// many screen capture buffers, without swapping the channels first
+pub fn encode_bgra(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    config: &WebPConfig,
+) -> Result<WebPMemory, EncodeError> {
+    encode_pixels(bytes, width, height, 4, config, 0.0, None, new_picture_bgra)
+}
+
+/// Encodes 8-bit YUV 4:2:0 planes, like the frames of a video decoder, without
+/// a round trip through RGB. `strides` are the bytes from one row to the next
+/// in the Y plane and in the U and V planes
+pub fn encode_yuv(
+    y: &[u8],
+    u: &[u8],
+    v: &[u8],
+    strides: (usize, usize),
+    width: u32,
+    height: u32,
+    config: &WebPConfig,
+) -> Result<WebPMemory, EncodeError> {
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(EncodeError::DimensionTooLarge { width, height });
+    }
+    let (y_stride, uv_stride) = strides;
+    let (width, height) = (width as usize, height as usize);
+    let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+    check_plane("y", y, y_stride, width, height)?;
+    check_plane("u", u, uv_stride, uv_width, uv_height)?;
+    check_plane("v", v, uv_stride, uv_width, uv_height)?;
+
+    validate_config(config)?;
+    unsafe {
+        let mut picture = alloc_picture_yuv(width as u32, height as u32)?;
+        copy_plane(y, y_stride, picture.y, picture.y_stride, width, height);
+        copy_plane(
+            u,
+            uv_stride,
+            picture.u,
+            picture.uv_stride,
+            uv_width,
+            uv_height,
+        );
+        copy_plane(
+            v,
+            uv_stride,
+            picture.v,
+            picture.uv_stride,
+            uv_width,
+            uv_height,
+        );
+        Ok(encode(&mut picture, config)?)
+    }
+}
+
+/// Checks that `plane` holds `rows` rows of `row_width` bytes, `stride` apart.
+/// The last row doesn't need the padding
+fn check_plane(
+    name: &'static str,
+    plane: &[u8],
+    stride: usize,
+    row_width: usize,
+    rows: usize,
+) -> Result<(), EncodeError> {
+    if stride < row_width {
+        return Err(EncodeError::InvalidStride {
+            plane: name,
+            stride,
+            row_width,
+        });
+    }
+    let expected = stride * rows.saturating_sub(1) + row_width;
+    if plane.len() < expected {
+        return Err(EncodeError::BufferSizeMismatch {
+            expected,
+            actual: plane.len(),
+        });
+    }
+    Ok(())
+}
 
+/// # Safety
+/// `destination` has to hold `rows` rows of `row_width` bytes,
+/// `destination_stride` apart
+unsafe fn copy_plane(
+    source: &[u8],
+    source_stride: usize,
+    destination: *mut u8,
+    destination_stride: c_int,
+    row_width: usize,
+    rows: usize,
+) {
+    let destination_stride = destination_stride as usize;
+    for row in 0..rows {
+        let source_row = &source[row * source_stride..][..row_width];
+        std::ptr::copy_nonoverlapping(
+            source_row.as_ptr(),
+            destination.add(row * destination_stride),
+            row_width,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_pixels(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    channels: usize,
+    config: &WebPConfig,
+    dithering: f32,
+    stats: Option<&mut WebPAuxStats>,
+    new_picture: unsafe fn(&[u8], u32, u32) -> Result<ManagedPicture, WebPEncodingError>,
+) -> Result<WebPMemory, EncodeError> {
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(EncodeError::DimensionTooLarge { width, height });
+    }
+    let expected = width as usize * height as usize * channels;
+    if bytes.len() != expected {
+        return Err(EncodeError::BufferSizeMismatch {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+
+    validate_config(config)?;
     unsafe {
-        let mut picture = new_picture(&img, width, height);
-        let result = encode(&mut picture, config);
-        result
+        let mut picture = new_picture(bytes, width, height)?;
+        let is_sharp_yuv = config.use_sharp_yuv != 0 || config.preprocessing & 4 != 0;
+        if dithering > 0.0 && config.lossless == 0 && !is_sharp_yuv && picture.use_argb == 1 {
+            dither_to_yuv(&mut picture, dithering)?;
+        }
+        if let Some(stats) = stats {
+            picture.stats = stats;
+        }
+        Ok(encode(&mut picture, config)?)
+    }
+}
+
+/// Converts an RGB picture to YUV 4:2:0 with `dithering`. `WebPEncode` takes
+/// YUV as it is, its own dithering only comes with an amount picked from the
+/// quality
+unsafe fn dither_to_yuv(
+    picture: &mut WebPPicture,
+    dithering: f32,
+) -> Result<(), WebPEncodingError> {
+    if WebPPictureARGBToYUVADithered(picture, WebPEncCSP::WEBP_YUV420, dithering) == 0 {
+        return Err(picture.error_code);
+    }
+    picture.use_argb = 0;
+    Ok(())
+}
+
+/// Sets the quality and method of lossless encodes from libwebp's preset
+/// `level`, from 0 for the fastest to 9 for the smallest files
+pub fn lossless_preset(config: &mut WebPConfig, level: u8) -> Result<(), EncodeError> {
+    check_range("lossless level", level, 0..=9)?;
+    unsafe { WebPConfigLosslessPreset(config, level as c_int) };
+    Ok(())
+}
+
+/// Checks the settings we set against the ranges libwebp accepts, so a bad
+/// one is named instead of `WebPValidateConfig` only failing as a whole
+pub fn validate_config(config: &WebPConfig) -> Result<(), EncodeError> {
+    check_range("quality", config.quality, 0.0..=100.0)?;
+    check_range("method", config.method, 0..=6)?;
+    check_range("lossless", config.lossless, 0..=1)?;
+    check_range("preprocessing", config.preprocessing, 0..=7)?;
+    check_range("alpha_quality", config.alpha_quality, 0..=100)?;
+    check_range("thread_level", config.thread_level, 0..=1)?;
+    check_range("low_memory", config.low_memory, 0..=1)?;
+    check_range("qmax", config.qmax, 0..=100)?;
+    // libwebp rejects a range that's upside down, with no word on why
+    check_range("qmin", config.qmin, 0..=config.qmax)?;
+    Ok(())
+}
+
+fn check_range<T: PartialOrd + Display>(
+    setting: &'static str,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<(), EncodeError> {
+    if range.contains(&value) {
+        return Ok(());
+    }
+    Err(EncodeError::InvalidConfig {
+        setting,
+        expected: format!("{}..={}", range.start(), range.end()),
+        actual: value.to_string(),
+    })
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    /// WebP can't store images wider or taller than `WEBP_MAX_DIMENSION`
+    DimensionTooLarge {
+        width: u32,
+        height: u32,
+    },
+    /// The pixel buffer doesn't hold exactly `width * height` pixels
+    BufferSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// A row of a YUV plane is shorter than the image is wide
+    InvalidStride {
+        plane: &'static str,
+        stride: usize,
+        row_width: usize,
+    },
+    /// A config setting is out of the range libwebp accepts
+    InvalidConfig {
+        setting: &'static str,
+        expected: String,
+        actual: String,
+    },
+    Encoding(WebPEncodingError),
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            EncodeError::DimensionTooLarge { width, height } => write!(
+                f,
+                "{}x{} is too large, WebP images can be at most {}x{}",
+                width, height, WEBP_MAX_DIMENSION, WEBP_MAX_DIMENSION
+            ),
+            EncodeError::BufferSizeMismatch { expected, actual } => write!(
+                f,
+                "the pixel buffer holds {} bytes, expected {}",
+                actual, expected
+            ),
+            EncodeError::InvalidStride {
+                plane,
+                stride,
+                row_width,
+            } => write!(
+                f,
+                "the {} plane has a stride of {} bytes, shorter than its rows of {}",
+                plane, stride, row_width
+            ),
+            EncodeError::InvalidConfig {
+                setting,
+                expected,
+                actual,
+            } => write!(f, "{} must be {}, got {}", setting, expected, actual),
+            EncodeError::Encoding(error) => {
+                write!(f, "{:?}, {}", error, encoding_error_description(*error))
+            }
+        }
+    }
+}
+
+/// What libwebp's documentation says each error means
+fn encoding_error_description(error: WebPEncodingError) -> &'static str {
+    match error {
+        WebPEncodingError::VP8_ENC_OK => "no error",
+        WebPEncodingError::VP8_ENC_ERROR_OUT_OF_MEMORY => "out of memory for the encoder",
+        WebPEncodingError::VP8_ENC_ERROR_BITSTREAM_OUT_OF_MEMORY => {
+            "out of memory while flushing the bitstream"
+        }
+        WebPEncodingError::VP8_ENC_ERROR_NULL_PARAMETER => "a pointer parameter is null",
+        WebPEncodingError::VP8_ENC_ERROR_INVALID_CONFIGURATION => "the configuration is invalid",
+        WebPEncodingError::VP8_ENC_ERROR_BAD_DIMENSION => "the picture has invalid width or height",
+        WebPEncodingError::VP8_ENC_ERROR_PARTITION0_OVERFLOW => {
+            "the first partition is larger than 512 KB, try a lower quality or more segments"
+        }
+        WebPEncodingError::VP8_ENC_ERROR_PARTITION_OVERFLOW => "a partition is larger than 16 MB",
+        WebPEncodingError::VP8_ENC_ERROR_BAD_WRITE => "the output couldn't be written",
+        WebPEncodingError::VP8_ENC_ERROR_FILE_TOO_BIG => "the file is larger than 4 GB",
+        WebPEncodingError::VP8_ENC_ERROR_USER_ABORT => "aborted by the user",
+        WebPEncodingError::VP8_ENC_ERROR_LAST => "unknown error",
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<WebPEncodingError> for EncodeError {
+    fn from(error: WebPEncodingError) -> Self {
+        EncodeError::Encoding(error)
     }
 }
 
 /// This struct represents a safe wrapper around memory owned by libwebp.
 /// Its data contents can be accessed through the Deref and DerefMut traits.
-pub struct WebPMemory(pub(crate) *mut u8, pub usize);
+/// The fields stay private, a wrong size would read out of bounds
+pub struct WebPMemory(*mut u8, usize);
+
+impl WebPMemory {
+    pub fn len(&self) -> usize {
+        self.1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.1 == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    /// Copies the bytes out, so the libwebp memory can be freed
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_slice().to_vec()
+    }
+}
 
 impl Debug for WebPMemory {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
@@ -64,10 +629,11 @@ unsafe fn encode(
     WebPMemoryWriterInit(ww.as_mut_ptr());
     picture.writer = Some(WebPMemoryWrite);
     picture.custom_ptr = ww.as_mut_ptr() as *mut std::ffi::c_void;
+    // Unlike most of libwebp, `WebPEncode` returns 0 on failure
     let status = libwebp_sys::WebPEncode(config, picture);
     let ww = ww.assume_init();
-    let mem = WebPMemory(ww.mem, ww.size as usize);
-    if status != VP8StatusCode::VP8_STATUS_OK as i32 {
+    let mem = WebPMemory(ww.mem, ww.size);
+    if status != 0 {
         Ok(mem)
     } else {
         Err(picture.error_code)
@@ -97,11 +663,94 @@ impl DerefMut for ManagedPicture {
     }
 }
 
-pub unsafe fn new_picture(image: &[u8], width: u32, height: u32) -> ManagedPicture {
+/// # Safety
+/// `image` has to hold `width * height` tightly packed RGB pixels
+pub unsafe fn new_picture(
+    image: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ManagedPicture, WebPEncodingError> {
+    import_picture(image, width, height, 3, WebPPictureImportRGB)
+}
+
+/// # Safety
+/// `image` has to hold `width * height` tightly packed RGBA pixels
+pub unsafe fn new_picture_rgba(
+    image: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ManagedPicture, WebPEncodingError> {
+    import_picture(image, width, height, 4, WebPPictureImportRGBA)
+}
+
+/// # Safety
+/// `image` has to hold `width * height` tightly packed BGRA pixels
+pub unsafe fn new_picture_bgra(
+    image: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ManagedPicture, WebPEncodingError> {
+    import_picture(image, width, height, 4, WebPPictureImportBGRA)
+}
+
+/// Builds a YUV picture with the gray levels in Y and neutral chroma
+///
+/// # Safety
+/// `image` has to hold `width * height` 8-bit gray levels
+pub unsafe fn new_picture_luma(
+    image: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ManagedPicture, WebPEncodingError> {
+    let picture = alloc_picture_yuv(width, height)?;
+
+    let (width, height) = (width as usize, height as usize);
+    let y_stride = picture.y_stride as usize;
+    let y = std::slice::from_raw_parts_mut(picture.y, y_stride * height);
+    for (row, pixels) in image.chunks_exact(width).enumerate() {
+        for (x, &luma) in pixels.iter().enumerate() {
+            // Y uses the limited 16..=235 range, the same as WebP's RGB import
+            y[row * y_stride + x] = (16 + (luma as u32 * 219 + 127) / 255) as u8;
+        }
+    }
+
+    let uv_size = picture.uv_stride as usize * height.div_ceil(2);
+    std::slice::from_raw_parts_mut(picture.u, uv_size).fill(128);
+    std::slice::from_raw_parts_mut(picture.v, uv_size).fill(128);
+
+    Ok(picture)
+}
+
+/// Allocates a YUV 4:2:0 picture for the caller to fill in
+unsafe fn alloc_picture_yuv(width: u32, height: u32) -> Result<ManagedPicture, WebPEncodingError> {
+    let mut picture = WebPPicture::new().unwrap();
+    picture.use_argb = 0;
+    picture.colorspace = WebPEncCSP::WEBP_YUV420;
+    picture.width = width as i32;
+    picture.height = height as i32;
+    if WebPPictureAlloc(&mut picture) == 0 {
+        let error = picture.error_code;
+        WebPPictureFree(&mut picture);
+        return Err(error);
+    }
+    Ok(ManagedPicture(picture))
+}
+
+unsafe fn import_picture(
+    image: &[u8],
+    width: u32,
+    height: u32,
+    channels: i32,
+    import: unsafe extern "C" fn(*mut WebPPicture, *const u8, c_int) -> c_int,
+) -> Result<ManagedPicture, WebPEncodingError> {
     let mut picture = WebPPicture::new().unwrap();
     picture.use_argb = 1;
     picture.width = width as i32;
     picture.height = height as i32;
-    WebPPictureImportRGB(&mut picture, image.as_ptr(), width as i32 * 3);
-    ManagedPicture(picture)
+    if import(&mut picture, image.as_ptr(), width as i32 * channels) == 0 {
+        let error = picture.error_code;
+        WebPPictureFree(&mut picture);
+        return Err(error);
+    }
+    Ok(ManagedPicture(picture))
 }
@@ -0,0 +1,675 @@
+use std::{
+    collections::HashSet,
+    ffi::c_int,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use image::{GenericImageView, ImageFormat};
+use libwebp_sys::{WebPConfig, WebPDecodeRGBA, WebPEncodingError, WebPFree};
+use webp::{
+    args::Cli,
+    image_processing::{self, ConvertOptions},
+    webp_wrapper::{
+        encode_bgra, encode_to_writer, encode_yuv, image_to_webp, image_to_webp_dithered,
+        image_to_webp_with_stats, EncodeError, EncodeOptions, WebPEncoder,
+    },
+};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Config and options as the command line builds them from `args`
+fn settings(args: &[&str]) -> (WebPConfig, ConvertOptions) {
+    let cli = Cli::parse_from(["webp", "input"].iter().chain(args));
+    (
+        image_processing::generate_config(&cli, Some(1)),
+        image_processing::generate_options(&cli),
+    )
+}
+
+/// Decodes `webp` to its dimensions and RGBA pixels
+fn decode(webp: &[u8]) -> (u32, u32, Vec<u8>) {
+    let (mut width, mut height): (c_int, c_int) = (0, 0);
+    unsafe {
+        let pixels = WebPDecodeRGBA(webp.as_ptr(), webp.len(), &mut width, &mut height);
+        assert!(!pixels.is_null(), "the output is not a valid WebP");
+        let rgba =
+            std::slice::from_raw_parts(pixels, width as usize * height as usize * 4).to_vec();
+        WebPFree(pixels as _);
+        (width as u32, height as u32, rgba)
+    }
+}
+
+fn max_difference(a: &[u8], b: &[u8]) -> u8 {
+    a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+}
+
+#[test]
+fn png_with_alpha_is_lossless() {
+    let img = image::open(fixture("alpha.png")).unwrap();
+    let expected = img.to_rgba8().into_raw();
+    let (config, _) = settings(&[]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (width, height, rgba) = decode(&webp);
+
+    assert_eq!((width, height), (24, 16));
+    for (actual, expected) in rgba.chunks_exact(4).zip(expected.chunks_exact(4)) {
+        assert_eq!(actual[3], expected[3]);
+        // libwebp is free to change the color of fully transparent pixels
+        if expected[3] > 0 {
+            assert_eq!(actual, expected);
+        }
+    }
+}
+
+#[test]
+fn lossy_keeps_soft_alpha_edges_lossless() {
+    let img = image::open(fixture("alpha.png")).unwrap();
+    let expected = img.to_rgba8().into_raw();
+    let (config, _) = settings(&["--quality", "50", "--lossy"]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (_, _, rgba) = decode(&webp);
+
+    for (actual, expected) in rgba.chunks_exact(4).zip(expected.chunks_exact(4)) {
+        assert_eq!(actual[3], expected[3]);
+    }
+}
+
+#[test]
+fn lossless_alpha_keeps_soft_edges_with_lossy_colors() {
+    let img = image::open(fixture("alpha.png")).unwrap();
+    let expected = img.to_rgba8().into_raw();
+    let (config, _) = settings(&["--lossless-alpha"]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (_, _, rgba) = decode(&webp);
+
+    assert_eq!(config.lossless, 0);
+    assert_eq!(config.quality, 100.0);
+    assert_eq!((config.alpha_compression, config.alpha_quality), (1, 100));
+    for (actual, expected) in rgba.chunks_exact(4).zip(expected.chunks_exact(4)) {
+        assert_eq!(actual[3], expected[3]);
+    }
+}
+
+#[test]
+fn jpeg_lossy_is_close() {
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    let expected = img.to_rgba8().into_raw();
+    let (config, _) = settings(&["--quality", "90", "--lossy"]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (width, height, rgba) = decode(&webp);
+
+    assert_eq!((width, height), (24, 16));
+    assert!(max_difference(&rgba, &expected) <= 16);
+}
+
+#[test]
+fn grayscale_lossy_stays_gray() {
+    let img = image::open(fixture("gray.png")).unwrap();
+    assert!(matches!(img, image::DynamicImage::ImageLuma8(_)));
+    let expected = img.to_luma8().into_raw();
+    let (config, _) = settings(&["--quality", "90", "--lossy"]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (width, height, rgba) = decode(&webp);
+
+    assert_eq!((width, height), (24, 16));
+    for (pixel, luma) in rgba.chunks_exact(4).zip(expected) {
+        assert!(max_difference(&pixel[..3], &[luma; 3]) <= 8);
+        assert_eq!(pixel[3], 255);
+    }
+}
+
+#[test]
+fn grayscale_lossless_is_exact() {
+    let img = image::open(fixture("gray.png")).unwrap();
+    let expected = img.to_luma8().into_raw();
+    let (config, _) = settings(&[]);
+
+    let webp = image_to_webp(img, &config).unwrap();
+    let (_, _, rgba) = decode(&webp);
+
+    for (pixel, luma) in rgba.chunks_exact(4).zip(expected) {
+        assert_eq!(pixel, [luma, luma, luma, 255]);
+    }
+}
+
+#[test]
+fn bmp_tga_and_tiff_are_converted() {
+    let (config, options) = settings(&[]);
+    for (name, format) in [
+        ("rgb.bmp", ImageFormat::Bmp),
+        ("rgb.tga", ImageFormat::Tga),
+        ("rgb.tiff", ImageFormat::Tiff),
+    ] {
+        let expected = image::open(fixture(name)).unwrap();
+        let encoded = image_processing::encode_to_memory(&fixture(name), &config, &options)
+            .unwrap_or_else(|error| panic!("{}: {}", name, error));
+        let (width, height, rgba) = decode(&encoded.bytes);
+
+        assert_eq!(encoded.format, Some(format), "{}", name);
+        assert_eq!(encoded.dimensions, expected.dimensions(), "{}", name);
+        assert_eq!((width, height), expected.dimensions(), "{}", name);
+        assert_eq!(rgba, expected.to_rgba8().into_raw(), "{}", name);
+    }
+}
+
+#[test]
+fn background_is_composited_under_transparent_pixels() {
+    let (config, options) = settings(&["--background", "#ffffff"]);
+
+    let encoded =
+        image_processing::encode_to_memory(&fixture("half_red.png"), &config, &options).unwrap();
+    let (width, height, rgba) = decode(&encoded.bytes);
+
+    assert_eq!((width, height), (4, 4));
+    for pixel in rgba.chunks_exact(4) {
+        assert_eq!(pixel, [255, 127, 127, 255]);
+    }
+}
+
+#[test]
+fn invalid_config_is_rejected() {
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    let (mut config, _) = settings(&[]);
+    config.method = 7;
+
+    let error = image_to_webp(img, &config).unwrap_err();
+
+    assert!(matches!(
+        error,
+        EncodeError::InvalidConfig {
+            setting: "method",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn qmin_above_qmax_is_rejected() {
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    let (config, _) = settings(&["--qmin", "80", "--qmax", "50"]);
+
+    let error = image_to_webp(img, &config).unwrap_err();
+
+    assert!(matches!(
+        error,
+        EncodeError::InvalidConfig {
+            setting: "qmin",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn yuv_planes_with_padded_rows_are_encoded() {
+    // Mid gray in the limited range, rows padded to 32 and 16 bytes
+    let y = [126; 32 * 16];
+    let uv = [128; 16 * 8];
+    let (config, _) = settings(&["--quality", "90", "--lossy"]);
+
+    let webp = encode_yuv(&y, &uv, &uv, (32, 16), 24, 16, &config).unwrap();
+    let (width, height, rgba) = decode(&webp);
+
+    assert_eq!((width, height), (24, 16));
+    for pixel in rgba.chunks_exact(4) {
+        assert!(max_difference(pixel, &[128, 128, 128, 255]) <= 2);
+    }
+}
+
+#[test]
+fn bgra_channels_are_swapped_by_libwebp() {
+    // Blue, then half transparent red
+    let bgra = [255, 0, 0, 255, 0, 0, 255, 128].repeat(8);
+    let (config, _) = settings(&[]);
+
+    let webp = encode_bgra(&bgra, 4, 4, &config).unwrap();
+    let (_, _, rgba) = decode(&webp);
+
+    assert_eq!(rgba, [0, 0, 255, 255, 255, 0, 0, 128].repeat(8));
+    assert!(encode_bgra(&bgra[1..], 4, 4, &config).is_err());
+}
+
+#[test]
+fn short_yuv_plane_is_rejected() {
+    let y = [126; 24 * 16];
+    let uv = [128; 12 * 8];
+    let (config, _) = settings(&[]);
+
+    let error = encode_yuv(&y, &uv, &uv[1..], (24, 12), 24, 16, &config).unwrap_err();
+
+    assert!(matches!(
+        error,
+        EncodeError::BufferSizeMismatch {
+            expected: 96,
+            actual: 95
+        }
+    ));
+}
+
+#[test]
+fn thumbnail_never_upscales_by_default() {
+    let (config, options) = settings(&["--thumbnail", "4000"]);
+
+    let encoded =
+        image_processing::encode_to_memory(&fixture("rgb.bmp"), &config, &options).unwrap();
+    let (width, height, _) = decode(&encoded.bytes);
+
+    assert_eq!((width, height), (16, 16));
+}
+
+#[test]
+fn thumbnail_upscales_when_allowed() {
+    let (config, options) = settings(&["--thumbnail", "32", "--allow-upscale"]);
+
+    let encoded =
+        image_processing::encode_to_memory(&fixture("rgb.bmp"), &config, &options).unwrap();
+    let (width, height, _) = decode(&encoded.bytes);
+
+    assert_eq!((width, height), (32, 32));
+}
+
+#[test]
+fn output_is_deterministic_whatever_the_threads() {
+    for quality in ["100", "75"] {
+        let encode = |threads_webp: &str| {
+            let args = ["--quality", quality, "--threads-webp", threads_webp];
+            let (config, options) = settings(&args);
+            image_processing::encode_to_memory(&fixture("photo.jpg"), &config, &options)
+                .unwrap()
+                .bytes
+        };
+
+        let single_threaded = encode("0");
+        assert_eq!(single_threaded, encode("0"), "quality {}", quality);
+        assert_eq!(single_threaded, encode("1"), "quality {}", quality);
+    }
+}
+
+#[test]
+fn presets_encode() {
+    for (name, options) in [
+        ("photo", EncodeOptions::photo()),
+        ("lossless", EncodeOptions::lossless()),
+        ("icon", EncodeOptions::icon()),
+    ] {
+        let img = image::open(fixture("alpha.png")).unwrap();
+        let webp =
+            image_to_webp(img, &options).unwrap_or_else(|error| panic!("{}: {}", name, error));
+        let (width, height, _) = decode(&webp);
+
+        assert_eq!((width, height), (24, 16), "{}", name);
+    }
+    assert_eq!(EncodeOptions::lossless().lossless, 1);
+    assert_eq!(EncodeOptions::photo().lossless, 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_file_names_are_converted() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    use webp::{image_processing::RunningTotals, logging::Logging};
+
+    let dir = std::env::temp_dir().join(format!("webp-non-utf8-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join(OsStr::from_bytes(b"caf\xe9.png"));
+    std::fs::copy(fixture("alpha.png"), &input).unwrap();
+    let (config, options) = settings(&[]);
+
+    let totals = image_processing::convert_file_all(
+        [input.clone()].into_iter(),
+        &HashSet::new(),
+        &dir,
+        &dir,
+        &config,
+        &options,
+        &Logging::start(false, true, false),
+        &RunningTotals::default(),
+    );
+    let converted = input.with_extension("webp").is_file();
+    let missing = Cli::parse_from([OsStr::new("webp"), OsStr::from_bytes(b"missing\xff")]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(totals.count, 1);
+    assert!(converted);
+    assert!(missing.input_paths().is_err());
+}
+
+#[test]
+fn failed_writes_are_left_out_of_the_manifest() {
+    use std::sync::Arc;
+    use webp::{image_processing::RunningTotals, logging::Logging, manifest::Manifest};
+
+    let dir = std::env::temp_dir().join(format!("webp-manifest-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (input_dir, output_dir) = (dir.join("input"), dir.join("output"));
+    std::fs::create_dir_all(&input_dir).unwrap();
+    let input = input_dir.join("alpha.png");
+    std::fs::copy(fixture("alpha.png"), &input).unwrap();
+    // A directory where the output should be written, which fails even as root
+    std::fs::create_dir_all(output_dir.join("alpha.webp")).unwrap();
+    let manifest_path = dir.join("manifest.txt");
+    let (config, mut options) = settings(&[]);
+    options.manifest = Some(Arc::new(Manifest::open(&manifest_path).unwrap()));
+
+    image_processing::convert_file_all(
+        [input.clone()].into_iter(),
+        &HashSet::new(),
+        &input_dir,
+        &output_dir,
+        &config,
+        &options,
+        &Logging::start(false, true, false),
+        &RunningTotals::default(),
+    );
+    let recorded = Manifest::open(&manifest_path).unwrap().contains(&input);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!recorded);
+}
+
+#[test]
+fn content_cache_misses_when_lossless_settings_change() {
+    use webp::{image_processing::RunningTotals, logging::Logging};
+
+    let dir = std::env::temp_dir().join(format!("webp-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (input_dir, output_dir) = (dir.join("input"), dir.join("output"));
+    std::fs::create_dir_all(&input_dir).unwrap();
+    let input = input_dir.join("alpha.png");
+    std::fs::copy(fixture("alpha.png"), &input).unwrap();
+    let cache = dir.join("cache");
+    let convert = |args: &[&str]| {
+        let args: Vec<&str> = ["--content-cache", cache.to_str().unwrap()]
+            .into_iter()
+            .chain(args.iter().copied())
+            .collect();
+        let (config, options) = settings(&args);
+        image_processing::convert_file_all(
+            [input.clone()].into_iter(),
+            &HashSet::new(),
+            &input_dir,
+            &output_dir,
+            &config,
+            &options,
+            &Logging::start(false, true, false),
+            &RunningTotals::default(),
+        );
+        std::fs::read(output_dir.join("alpha.webp")).unwrap()
+    };
+
+    let lossless = convert(&["--quality", "80", "--quality-png", "100"]);
+    let lossy = convert(&["--quality", "80", "--quality-png", "100", "--lossy"]);
+    let fastest = convert(&[
+        "--quality",
+        "80",
+        "--quality-png",
+        "100",
+        "--lossless-level",
+        "0",
+    ]);
+    let smallest = convert(&[
+        "--quality",
+        "80",
+        "--quality-png",
+        "100",
+        "--lossless-level",
+        "9",
+    ]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(&lossless[12..16], b"VP8L");
+    assert_ne!(&lossy[12..16], b"VP8L");
+    assert_ne!(fastest, smallest);
+}
+
+#[test]
+fn source_that_would_be_overwritten_is_skipped() {
+    use webp::{image_processing::RunningTotals, logging::Logging};
+
+    let dir = std::env::temp_dir().join(format!("webp-same-file-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("alpha.webp");
+    std::fs::copy(fixture("alpha.png"), &input).unwrap();
+    let (config, options) = settings(&["--force"]);
+
+    let totals = image_processing::convert_file_all(
+        [input.clone()].into_iter(),
+        &HashSet::new(),
+        &dir,
+        &dir,
+        &config,
+        &options,
+        &Logging::start(false, true, false),
+        &RunningTotals::default(),
+    );
+    let source = std::fs::read(&input).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!((totals.count, totals.skipped), (0, 1));
+    assert_eq!(source, std::fs::read(fixture("alpha.png")).unwrap());
+}
+
+#[test]
+fn only_files_that_arent_images_are_copied_unconverted() {
+    use webp::{image_processing::RunningTotals, logging::Logging};
+
+    let dir = std::env::temp_dir().join(format!("webp-unconvertible-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (input_dir, output_dir) = (dir.join("input"), dir.join("output"));
+    std::fs::create_dir_all(&input_dir).unwrap();
+    let image = input_dir.join("alpha.png");
+    std::fs::copy(fixture("alpha.png"), &image).unwrap();
+    let text = input_dir.join("notes.txt");
+    std::fs::write(&text, "not an image").unwrap();
+    let (config, options) = settings(&["--copy-unconvertible", "--max-pixels", "10"]);
+
+    image_processing::convert_file_all(
+        [image, text].into_iter(),
+        &HashSet::new(),
+        &input_dir,
+        &output_dir,
+        &config,
+        &options,
+        &Logging::start(false, true, false),
+        &RunningTotals::default(),
+    );
+    let text_copied = output_dir.join("notes.txt").is_file();
+    let image_copied = output_dir.join("alpha.png").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(text_copied);
+    assert!(!image_copied, "an image refused by --max-pixels was copied");
+}
+
+#[test]
+fn encoding_errors_keep_libwebp_name() {
+    let error = EncodeError::Encoding(WebPEncodingError::VP8_ENC_ERROR_PARTITION0_OVERFLOW);
+
+    assert!(error
+        .to_string()
+        .starts_with("VP8_ENC_ERROR_PARTITION0_OVERFLOW, "));
+}
+
+#[test]
+fn image_encoder_writes_webp() {
+    let img = image::open(fixture("alpha.png")).unwrap();
+    let expected = img.to_rgba8().into_raw();
+    let mut webp = Vec::new();
+
+    img.write_with_encoder(WebPEncoder::new(&mut webp, *EncodeOptions::lossless()))
+        .unwrap();
+    let (width, height, rgba) = decode(&webp);
+
+    assert_eq!((width, height), (24, 16));
+    for (actual, expected) in rgba.chunks_exact(4).zip(expected.chunks_exact(4)) {
+        if expected[3] > 0 {
+            assert_eq!(actual, expected);
+        }
+    }
+}
+
+#[test]
+fn frame_selects_animation_frames_and_tiff_pages() {
+    let (config, options) = settings(&["--frame", "1"]);
+    for name in ["frames.gif", "pages.tiff"] {
+        let encoded = image_processing::encode_to_memory(&fixture(name), &config, &options)
+            .unwrap_or_else(|error| panic!("{}: {}", name, error));
+        let (_, _, rgba) = decode(&encoded.bytes);
+
+        assert_eq!(&rgba[..4], [0, 0, 255, 255], "{}", name);
+    }
+
+    let (config, options) = settings(&["--frame", "2"]);
+    for name in ["frames.gif", "pages.tiff", "rgb.bmp"] {
+        assert!(
+            image_processing::encode_to_memory(&fixture(name), &config, &options).is_err(),
+            "{}",
+            name
+        );
+    }
+
+    let heif = std::env::temp_dir().join(format!("webp-frame-{}.heic", std::process::id()));
+    std::fs::write(&heif, b"not decoded before the frame is checked").unwrap();
+    let encoded = image_processing::encode_to_memory(&heif, &config, &options);
+    std::fs::remove_file(&heif).unwrap();
+    let Err(error) = encoded else {
+        panic!("frame 2 of a HEIF file was converted");
+    };
+    assert!(error.to_string().contains("frame 0"), "{}", error);
+}
+
+#[test]
+fn encode_to_writer_writes_the_same_bytes() {
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    let config = *EncodeOptions::photo();
+    let mut written = Vec::new();
+
+    encode_to_writer(&img, &config, &mut written).unwrap();
+
+    assert_eq!(written, image_to_webp(img, &config).unwrap().as_slice());
+}
+
+#[test]
+fn list_reports_outputs_that_cant_be_written() {
+    let root = fixture("nested");
+    let walk_options = webp::file_utils::WalkOptions {
+        max_depth: 0,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+    // A file where the output directory should be
+    let paths = webp::file_utils::Paths::build(
+        vec![root],
+        fixture("photo.jpg"),
+        &walk_options,
+        webp::args::Sort::Name,
+        false,
+        &|| false,
+    )
+    .unwrap();
+    let (_, options) = settings(&[]);
+
+    assert!(image_processing::list(&paths, &options).is_err());
+}
+
+#[test]
+fn lossless_applies_to_every_format() {
+    let (config, options) = settings(&["--lossless"]);
+    let encoded =
+        image_processing::encode_to_memory(&fixture("photo.jpg"), &config, &options).unwrap();
+    let with_jpeg_quality =
+        Cli::try_parse_from(["webp", "input", "--lossless", "--quality-jpeg", "80"]);
+
+    assert_eq!(&encoded.bytes[12..16], b"VP8L");
+    assert!(with_jpeg_quality.is_err());
+}
+
+#[test]
+fn smart_default_keeps_only_png_lossless() {
+    let (config, options) = settings(&["--smart-default"]);
+    for (name, chunk) in [
+        ("alpha.png", b"VP8L"),
+        ("photo.jpg", b"VP8 "),
+        ("rgb.bmp", b"VP8 "),
+    ] {
+        let encoded =
+            image_processing::encode_to_memory(&fixture(name), &config, &options).unwrap();
+
+        assert_eq!(&encoded.bytes[12..16], chunk, "{}", name);
+    }
+}
+
+#[test]
+fn dithering_warns_only_when_every_image_is_lossless() {
+    let warns = |args: &[&str]| {
+        Cli::parse_from(
+            ["webp", "input", "--dither-strength", "50"]
+                .iter()
+                .chain(args),
+        )
+        .warnings()
+        .iter()
+        .any(|warning| warning.contains("--dither-strength"))
+    };
+
+    assert!(warns(&[]));
+    assert!(!warns(&["--quality", "80"]));
+    assert!(!warns(&["--quality-jpeg", "80"]));
+}
+
+#[test]
+fn dithering_breaks_up_gradient_bands() {
+    // A slow gradient, a level every 8 pixels, which lossy flattens into bands
+    let gradient = image::RgbImage::from_fn(256, 16, |x, _| image::Rgb([96 + x as u8 / 8; 3]));
+    let (config, _) = settings(&["--quality", "30", "--lossy"]);
+    let longest_band = |dithering| {
+        let img = image::DynamicImage::ImageRgb8(gradient.clone());
+        let webp = image_to_webp_dithered(img, &config, dithering).unwrap();
+        let (_, _, rgba) = decode(&webp);
+        let row: Vec<u8> = rgba[..256 * 4]
+            .chunks_exact(4)
+            .map(|pixel| pixel[1])
+            .collect();
+        row.chunk_by(|a, b| a == b).map(<[u8]>::len).max().unwrap()
+    };
+
+    assert!(longest_band(1.0) < longest_band(0.0));
+}
+
+#[test]
+fn target_size_picks_the_highest_quality_that_fits() {
+    let (config, options) = settings(&["--target-size", "150"]);
+
+    let encoded =
+        image_processing::encode_to_memory(&fixture("photo.jpg"), &config, &options).unwrap();
+
+    assert!(encoded.bytes.len() <= 150);
+    assert_eq!(encoded.config.lossless, 0);
+    let mut higher = encoded.config;
+    higher.quality += 1.0;
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    assert!(image_to_webp(img, &higher).unwrap().len() > 150);
+}
+
+#[test]
+fn stats_describe_the_encode() {
+    let img = image::open(fixture("photo.jpg")).unwrap();
+    let (config, _) = settings(&["--quality", "90", "--lossy"]);
+
+    let (webp, stats) = image_to_webp_with_stats(img, &config, 0.0).unwrap();
+
+    assert_eq!(stats.coded_size as usize, webp.len());
+    // Y, U, V, all of them and alpha
+    assert!(stats.PSNR[3] > 30.0);
+}
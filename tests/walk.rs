@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use webp::{
+    args::{Cli, Sort},
+    file_utils::{self, Paths, WalkOptions},
+};
+
+/// Images found under the nested fixture, relative to it
+fn walk(max_depth: u16) -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nested");
+    let walk_options = WalkOptions {
+        max_depth,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+    let paths = Paths::build(
+        vec![root.clone()],
+        root.clone(),
+        &walk_options,
+        Sort::Name,
+        false,
+        &|| false,
+    )
+    .unwrap();
+    paths
+        .input
+        .images
+        .iter()
+        .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+        .collect()
+}
+
+#[test]
+fn depth_0_takes_only_the_top_level() {
+    assert_eq!(walk(0), [PathBuf::from("top.png")]);
+}
+
+#[test]
+fn depth_1_goes_one_directory_down() {
+    assert_eq!(
+        walk(1),
+        [PathBuf::from("one/a.png"), PathBuf::from("top.png")]
+    );
+}
+
+#[test]
+fn depth_2_goes_two_directories_down() {
+    assert_eq!(
+        walk(2),
+        [
+            PathBuf::from("one/a.png"),
+            PathBuf::from("one/two/b.png"),
+            PathBuf::from("top.png")
+        ]
+    );
+}
+
+#[test]
+fn single_file_is_taken_at_any_depth() {
+    let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nested/top.png");
+    let walk_options = WalkOptions {
+        max_depth: 0,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+    let paths = Paths::build(
+        vec![file.clone()],
+        file.clone(),
+        &walk_options,
+        Sort::Name,
+        false,
+        &|| false,
+    )
+    .unwrap();
+    assert_eq!(paths.input.images, [file]);
+}
+
+#[test]
+fn several_inputs_share_their_common_root() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nested");
+    let walk_options = WalkOptions {
+        max_depth: 0,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+    let inputs = vec![
+        root.join("one/two"),
+        root.join("top.png"),
+        root.join("one/two/b.png"),
+    ];
+    let paths = Paths::build(
+        inputs,
+        root.clone(),
+        &walk_options,
+        Sort::Name,
+        false,
+        &|| false,
+    )
+    .unwrap();
+
+    assert_eq!(paths.input.root, root);
+    assert_eq!(
+        paths.input.images,
+        [root.join("one/two/b.png"), root.join("top.png")]
+    );
+}
+
+#[test]
+fn glob_inputs_are_expanded() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nested");
+    let pattern = root.join("**/*.png");
+    let cli = Cli::parse_from(["webp".as_ref(), pattern.as_os_str()]);
+
+    let inputs = cli.input_paths().unwrap();
+
+    assert_eq!(
+        inputs,
+        [
+            root.join("one/a.png"),
+            root.join("one/two/b.png"),
+            root.join("one/two/three/c.png"),
+            root.join("top.png")
+        ]
+    );
+}
+
+#[test]
+fn output_is_rejected_with_outputs_beside_their_sources() {
+    let cli = Cli::parse_from(["webp", "input", "--output-mode", "beside", "-o", "out"]);
+
+    assert!(cli.output_path(&[PathBuf::from("input")]).is_err());
+}
+
+#[test]
+fn stopped_walk_finds_nothing_more() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nested");
+    let walk_options = WalkOptions {
+        max_depth: 8,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+
+    let paths = Paths::build(
+        vec![root.clone()],
+        root,
+        &walk_options,
+        Sort::Name,
+        false,
+        &|| true,
+    )
+    .unwrap();
+
+    assert!(paths.input.images.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_inputs_fail_while_unreadable_subdirectories_are_skipped() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let dir = std::env::temp_dir().join(format!("webp-unreadable-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let locked = dir.join("locked");
+    fs::create_dir_all(&locked).unwrap();
+    fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/alpha.png"),
+        dir.join("alpha.png"),
+    )
+    .unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    let walk_options = WalkOptions {
+        max_depth: 8,
+        exclude_dirs: Vec::new(),
+        hidden: false,
+    };
+    let build = |input: &Path| {
+        let input = input.to_path_buf();
+        Paths::build(
+            vec![input.clone()],
+            input,
+            &walk_options,
+            Sort::Name,
+            false,
+            &|| false,
+        )
+    };
+
+    // Permissions don't stop root from listing the directory
+    let locked_is_readable = locked.read_dir().is_ok();
+    let whole = build(&dir);
+    let lone = build(&locked);
+    let streamed = file_utils::spawn_walk(
+        vec![locked.clone()],
+        walk_options.clone(),
+        |_, _| {},
+        || false,
+    );
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    if locked_is_readable {
+        return;
+    }
+
+    let whole = whole.unwrap();
+    assert_eq!(whole.input.images.len(), 1);
+    assert_eq!(whole.input.unreadable.len(), 1);
+    assert_eq!(whole.input.unreadable[0].0, locked);
+    assert!(lone.is_err());
+    assert!(streamed.is_err());
+}